@@ -0,0 +1,79 @@
+//! Loading the files that extend the configuration, the mapping file and the
+//! origin rules: YAML or TOML, by their extension.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+
+/// Why a configuration file could not be loaded.
+#[derive(Debug)]
+pub struct ConfigFileError {
+    /// Which file, eg. `mapping file`.
+    pub file: &'static str,
+    pub kind: ConfigFileErrorKind,
+}
+
+#[derive(Debug)]
+pub enum ConfigFileErrorKind {
+    Read(io::Error),
+    Yaml(serde_yaml::Error),
+    Toml(toml::de::Error),
+    /// Not a `.yaml`, `.yml` or `.toml` file.
+    UnknownFormat(String),
+    Invalid(String),
+}
+
+impl ConfigFileError {
+    pub fn invalid(file: &'static str, msg: String) -> ConfigFileError {
+        ConfigFileError { file, kind: ConfigFileErrorKind::Invalid(msg) }
+    }
+}
+
+impl fmt::Display for ConfigFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ConfigFileErrorKind::Read(err) => write!(f, "Cannot read {}: {}", self.file, err),
+            ConfigFileErrorKind::Yaml(err) => write!(f, "Invalid {}: {}", self.file, err),
+            ConfigFileErrorKind::Toml(err) => write!(f, "Invalid {}: {}", self.file, err),
+            ConfigFileErrorKind::UnknownFormat(path) => {
+                write!(f, "Cannot read {} {}: should be .yaml, .yml or .toml", self.file, path)
+            },
+            ConfigFileErrorKind::Invalid(msg) => write!(f, "Invalid {}: {}", self.file, msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigFileError {}
+
+/// Load `file` from `path`, in the format given by its extension.
+pub fn load<T: DeserializeOwned>(path: &Path, file: &'static str) -> Result<T, ConfigFileError> {
+    let error = |kind| ConfigFileError { file, kind };
+    let content = fs::read_to_string(path).map_err(|err| error(ConfigFileErrorKind::Read(err)))?;
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("yaml") | Some("yml") => from_yaml(&content, file),
+        Some("toml") => toml::from_str(&content).map_err(|err| error(ConfigFileErrorKind::Toml(err))),
+        _ => Err(error(ConfigFileErrorKind::UnknownFormat(path.display().to_string()))),
+    }
+}
+
+pub fn from_yaml<T: DeserializeOwned>(content: &str, file: &'static str) -> Result<T, ConfigFileError> {
+    serde_yaml::from_str(content).map_err(|err| ConfigFileError { file, kind: ConfigFileErrorKind::Yaml(err) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Mapping;
+
+    #[test]
+    fn test_unknown_format() {
+        let err = load::<Mapping>(Path::new("Cargo.lock"), "mapping file").unwrap_err();
+        assert!(matches!(err.kind, ConfigFileErrorKind::UnknownFormat(_)));
+        assert_eq!(err.to_string(), "Cannot read mapping file Cargo.lock: should be .yaml, .yml or .toml");
+        let err = load::<Mapping>(Path::new("missing.yaml"), "mapping file").unwrap_err();
+        assert!(err.to_string().starts_with("Cannot read mapping file: "));
+    }
+}
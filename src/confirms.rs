@@ -0,0 +1,149 @@
+//! Bookkeeping for publisher confirms: which input delivery is waiting for
+//! the confirm of which published message.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use amiquip::{Confirm, ConfirmSmoother};
+
+/// Outcome for a delivery whose published message was confirmed, nacked or
+/// never confirmed in time.
+#[derive(Debug, PartialEq)]
+pub enum ConfirmOutcome {
+    Acked,
+    Nacked,
+    TimedOut,
+    /// Acked, but returned by the broker first because no queue took the
+    /// (`mandatory`) message.
+    Returned,
+}
+
+/// Keeps track of published messages awaiting a confirm from the broker,
+/// each with a tag (eg. the AMQP delivery it was transformed from).
+///
+/// The broker numbers confirms sequentially from 1 once confirm mode is
+/// enabled on a channel, so one `PendingConfirms` must be used per channel
+/// and must see every publish on it.
+pub struct PendingConfirms<T> {
+    timeout: Duration,
+    last_published: u64,
+    pending: BTreeMap<u64, (T, Instant)>,
+    smoother: ConfirmSmoother,
+}
+
+impl<T> PendingConfirms<T> {
+    pub fn new(timeout: Duration) -> PendingConfirms<T> {
+        PendingConfirms {
+            timeout,
+            last_published: 0,
+            pending: BTreeMap::new(),
+            smoother: ConfirmSmoother::new(),
+        }
+    }
+
+    /// Record that a message was just published for `tag`.
+    pub fn published(&mut self, tag: T) {
+        self.last_published += 1;
+        self.pending.insert(self.last_published, (tag, Instant::now()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Process a (possibly multiple or out of order) confirm from the broker.
+    /// Confirms for messages that already timed out are ignored.
+    pub fn confirm(&mut self, confirm: Confirm) -> Vec<(T, ConfirmOutcome)> {
+        let mut outcomes = Vec::new();
+        for confirm in self.smoother.process(confirm) {
+            let (delivery_tag, outcome) = match confirm {
+                Confirm::Ack(payload) => (payload.delivery_tag, ConfirmOutcome::Acked),
+                Confirm::Nack(payload) => (payload.delivery_tag, ConfirmOutcome::Nacked),
+            };
+            match self.pending.remove(&delivery_tag) {
+                Some((tag, _)) => outcomes.push((tag, outcome)),
+                None => debug!("Ignoring late confirm for message {}", delivery_tag),
+            }
+        }
+        outcomes
+    }
+
+    /// Remove and return the messages that have waited longer than the
+    /// timeout.
+    pub fn expire(&mut self) -> Vec<(T, ConfirmOutcome)> {
+        let timeout = self.timeout;
+        let expired: Vec<u64> = self.pending.iter()
+            .take_while(|(_, (_, published))| published.elapsed() >= timeout)
+            .map(|(delivery_tag, _)| *delivery_tag)
+            .collect();
+        expired.into_iter()
+            .filter_map(|delivery_tag| self.pending.remove(&delivery_tag))
+            .map(|(tag, _)| (tag, ConfirmOutcome::TimedOut))
+            .collect()
+    }
+
+    /// How long until the oldest pending message times out, if any.
+    pub fn time_until_expiry(&self) -> Option<Duration> {
+        self.pending.values().next().map(|(_, published)| {
+            self.timeout.checked_sub(published.elapsed()).unwrap_or_default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amiquip::ConfirmPayload;
+    use std::thread;
+
+    fn ack(delivery_tag: u64, multiple: bool) -> Confirm {
+        Confirm::Ack(ConfirmPayload { delivery_tag, multiple })
+    }
+
+    #[test]
+    fn test_confirm_multiple() {
+        let mut pending = PendingConfirms::new(Duration::from_secs(60));
+        pending.published("a");
+        pending.published("b");
+        pending.published("c");
+        assert_eq!(pending.confirm(ack(2, true)), vec![
+            ("a", ConfirmOutcome::Acked),
+            ("b", ConfirmOutcome::Acked),
+        ]);
+        assert_eq!(
+            pending.confirm(Confirm::Nack(ConfirmPayload { delivery_tag: 3, multiple: false })),
+            vec![("c", ConfirmOutcome::Nacked)]
+        );
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_confirm_out_of_order() {
+        let mut pending = PendingConfirms::new(Duration::from_secs(60));
+        pending.published("a");
+        pending.published("b");
+        assert!(pending.confirm(ack(2, false)).is_empty());
+        assert_eq!(pending.confirm(ack(1, false)), vec![
+            ("a", ConfirmOutcome::Acked),
+            ("b", ConfirmOutcome::Acked),
+        ]);
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut pending = PendingConfirms::new(Duration::from_millis(20));
+        assert_eq!(pending.time_until_expiry(), None);
+        pending.published("a");
+        assert!(pending.expire().is_empty());
+        thread::sleep(Duration::from_millis(25));
+        pending.published("b");
+        assert_eq!(pending.expire(), vec![("a", ConfirmOutcome::TimedOut)]);
+        assert_eq!(pending.len(), 1);
+        // A late confirm for the expired message is ignored.
+        assert_eq!(pending.confirm(ack(2, true)), vec![("b", ConfirmOutcome::Acked)]);
+    }
+}
@@ -0,0 +1,65 @@
+//! Connecting to the broker, shared by the consumer and the `replay`
+//! subcommand.
+
+use std::error::Error;
+use std::net::ToSocketAddrs;
+
+use amiquip::{Auth, Channel, Connection, ConnectionOptions, ConnectionTuning, Exchange};
+use native_tls::TlsConnector;
+
+use crate::Config;
+
+fn format_connection_string(config: &Config) -> String {
+    format!("amqp://{}:{}@{}:{}/{}",
+        config.amqp_user,
+        config.amqp_passwd,
+        config.amqp_host,
+        config.amqp_port(),
+        config.amqp_vhost)
+}
+
+/// Open the connection to the broker: plain `amqp`, or `amqps` with the TLS
+/// connector built from the `AMQP_TLS_*` settings.
+pub fn open_connection(config: &Config, tls: Option<&TlsConnector>) -> Result<Connection, Box<dyn Error>> {
+    let tls = match tls {
+        Some(tls) => tls,
+        None => return Ok(Connection::insecure_open(&format_connection_string(config))?),
+    };
+    let vhost = if config.amqp_vhost.is_empty() { "/" } else { config.amqp_vhost.as_str() };
+    let options = ConnectionOptions::default()
+        .auth(Auth::Plain {
+            username: config.amqp_user.clone(),
+            password: config.amqp_passwd.clone(),
+        })
+        .virtual_host(vhost);
+    let port: u16 = config.amqp_port().parse()?;
+    let mut last_err: Option<Box<dyn Error>> = None;
+    for addr in (config.amqp_host.as_str(), port).to_socket_addrs()? {
+        let result = mio::net::TcpStream::connect(&addr)
+            .map_err(Box::from)
+            .and_then(|stream| {
+                Connection::open_tls_stream(
+                    tls.clone(),
+                    &config.amqp_host,
+                    stream,
+                    options.clone(),
+                    ConnectionTuning::default(),
+                ).map_err(Box::from)
+            });
+        match result {
+            Ok(connection) => return Ok(connection),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| format!("{} did not resolve", config.amqp_host).into()))
+}
+
+/// The exchange to publish the JSON events to, which should already exist:
+/// the default exchange if `AMQP_OUT_EXCHANGE` is empty.
+pub fn output_exchange<'a>(channel: &'a Channel, config: &Config) -> amiquip::Result<Exchange<'a>> {
+    if config.amqp_out_exchange.is_empty() {
+        Ok(Exchange::direct(channel))
+    } else {
+        channel.exchange_declare_passive(config.amqp_out_exchange.as_str())
+    }
+}
@@ -0,0 +1,206 @@
+//! The `convert` subcommand: run XML messages from files (or stdin) through
+//! the transformation, without a broker.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::{parse_message, Source, TransformError, Transformer};
+
+pub const USAGE: &str = "Usage: amqp2elastic convert [--output FILE] [PATH...]
+
+Convert XML messages to the JSON documents amqp2elastic would emit, one per
+line. PATH is an XML file, a directory (all its *.xml files) or `-` for
+stdin, which is the default. Messages that would be rejected are reported on
+stderr, and make the exit code 1.";
+
+/// Where a message is read from.
+#[derive(Debug, PartialEq)]
+pub enum Input {
+    Stdin,
+    File(PathBuf),
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Input::Stdin => write!(f, "<stdin>"),
+            Input::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl Input {
+    fn read(&self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        match self {
+            Input::Stdin => io::stdin().read_to_end(&mut body)?,
+            Input::File(path) => File::open(path)?.read_to_end(&mut body)?,
+        };
+        Ok(body)
+    }
+}
+
+/// Expand the command line paths: directories to the `.xml` files in them
+/// (sorted), `-` to stdin.
+pub fn inputs(paths: &[String]) -> io::Result<Vec<Input>> {
+    if paths.is_empty() {
+        return Ok(vec![Input::Stdin]);
+    }
+    let mut inputs = Vec::new();
+    for path in paths {
+        if path == "-" {
+            inputs.push(Input::Stdin);
+            continue;
+        }
+        let path = Path::new(path);
+        if !path.is_dir() {
+            inputs.push(Input::File(path.to_path_buf()));
+            continue;
+        }
+        let mut files: Vec<PathBuf> = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<_>>()?;
+        files.retain(|file| file.is_file() && file.extension() == Some("xml".as_ref()));
+        files.sort();
+        inputs.extend(files.into_iter().map(Input::File));
+    }
+    Ok(inputs)
+}
+
+/// Why a message could not be converted.
+#[derive(Debug)]
+pub enum ConvertError {
+    Read(io::Error),
+    Transform(TransformError),
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConvertError::Read(err) => write!(f, "Cannot read: {}", err),
+            ConvertError::Transform(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Convert every input, writing the JSON documents to `output` as lines.
+/// Returns the inputs that failed.
+pub fn convert(
+    inputs: Vec<Input>,
+    transformer: &Transformer,
+    output: &mut dyn Write,
+) -> io::Result<Vec<(Input, ConvertError)>> {
+    let mut failures = Vec::new();
+    for input in inputs {
+        let body = match input.read() {
+            Ok(body) => body,
+            Err(err) => {
+                failures.push((input, ConvertError::Read(err)));
+                continue;
+            },
+        };
+        match parse_message(&body).and_then(|(xml, body)| transformer.handle_xml(xml, body, &Source::default())) {
+            Ok(document) => writeln!(output, "{}", document.json)?,
+            Err(err) => failures.push((input, ConvertError::Transform(err))),
+        }
+    }
+    output.flush()?;
+    Ok(failures)
+}
+
+/// Run the subcommand with the arguments following `convert`; returns the
+/// exit code.
+pub fn main(args: &[String], transformer: &Transformer) -> i32 {
+    let mut output: Option<&str> = None;
+    let mut paths = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => match args.next() {
+                Some(path) => output = Some(path),
+                None => return usage_error("--output needs a file", USAGE),
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return 0;
+            },
+            flag if flag.starts_with('-') && flag != "-" => {
+                return usage_error(&format!("Unknown option {}", flag), USAGE);
+            },
+            path => paths.push(path.to_string()),
+        }
+    }
+    let result = inputs(&paths).and_then(|inputs| match output {
+        Some(path) => convert(inputs, transformer, &mut BufWriter::new(File::create(path)?)),
+        None => convert(inputs, transformer, &mut io::stdout().lock()),
+    });
+    match result {
+        Ok(failures) if failures.is_empty() => 0,
+        Ok(failures) => {
+            for (input, err) in &failures {
+                eprintln!("{}: {}", input, err);
+            }
+            eprintln!("{} message(s) would be rejected", failures.len());
+            1
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            2
+        },
+    }
+}
+
+/// Report a wrong command line, with the subcommand's `usage`; the exit code.
+pub(crate) fn usage_error(message: &str, usage: &str) -> i32 {
+    eprintln!("{}\n\n{}", message, usage);
+    2
+}
+
+/// Write `value` as pretty-printed JSON, as the subcommands printing a
+/// template or schemas do.
+pub(crate) fn write_json(value: &Value, output: &mut dyn Write) -> io::Result<()> {
+    serde_json::to_writer_pretty(&mut *output, value)?;
+    writeln!(output)?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_convert_directory() {
+        let dir = env::temp_dir().join("amqp2elastic-convert-test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.xml"), r##"<essenceLinkedEvent>
+  <timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <file>AB00112233.mxf</file>
+  <mediaId>AB00112233</mediaId>
+</essenceLinkedEvent>"##).unwrap();
+        fs::write(dir.join("b.xml"), "<unknownEvent/>").unwrap();
+        fs::write(dir.join("notes.txt"), "not a message").unwrap();
+
+        let inputs = inputs(&[dir.to_str().unwrap().to_string()]).unwrap();
+        let mut output = Vec::new();
+        let failures = convert(inputs, &Transformer::default(), &mut output).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.lines().count(), 1);
+        assert!(output.contains(r#""media_id":"AB00112233""#));
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, Input::File(dir.join("b.xml")));
+        assert!(matches!(failures[0].1, ConvertError::Transform(TransformError::UnknownRootTag(_))));
+    }
+
+    #[test]
+    fn test_inputs_default_to_stdin() {
+        assert_eq!(inputs(&[]).unwrap(), vec![Input::Stdin]);
+        assert_eq!(inputs(&["-".to_string()]).unwrap(), vec![Input::Stdin]);
+    }
+}
@@ -0,0 +1,511 @@
+//! Pairing of requests with their responses by correlation id
+//! (`CORRELATION_TRACKING`), to measure how long VRT takes to answer and to
+//! find the requests that are never answered.
+//!
+//! When a response arrives for a pending request, a `correlation_completed`
+//! document with the round-trip duration (between the event timestamps) is
+//! emitted. A request still unanswered `CORRELATION_TIMEOUT_MS` after it was
+//! seen yields a `correlation_timed_out` document instead; a response that
+//! arrives after that is not paired anymore. These documents are kept until
+//! the output accepted them, and sent again otherwise (eg. after the
+//! connection was lost). The pending requests and unsent documents are saved
+//! to `CORRELATION_STATE_FILE` every `SAVE_INTERVAL` and on shutdown, so they
+//! survive a restart.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use chrono::{DateTime, Duration, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::event_id::sha256;
+use crate::{setting, timestamp, Config, Document, VrtEvent};
+
+/// How often the state file is written while consuming, at most.
+pub const SAVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// The longest `CORRELATION_TIMEOUT_MS`: a year.
+const MAX_TIMEOUT_MS: i64 = 365 * 24 * 3600 * 1000;
+
+/// The request event types with the response event type that answers them.
+pub const PAIRS: &[(&str, &str)] = &[
+    ("getMetadataRequest", "getMetadataResponse"),
+    ("triggerExportRequest", "triggerExportResponse"),
+];
+
+/// What the tracker needs to know of an event with a correlation id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Correlated {
+    pub event_name: String,
+    pub correlation_id: String,
+    pub event_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub media_id: Option<String>,
+}
+
+impl Correlated {
+    pub fn of(event: &dyn VrtEvent, event_id: &str) -> Option<Correlated> {
+        let correlation_id = event.correlation_id().filter(|id| !id.is_empty())?;
+        Some(Correlated {
+            event_name: event.name().to_string(),
+            correlation_id: correlation_id.to_string(),
+            event_id: event_id.to_string(),
+            timestamp: event.timestamp(),
+            media_id: event.media_id().map(str::to_string),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct PendingRequest {
+    event_name: String,
+    correlation_id: String,
+    event_id: String,
+    #[serde(with = "timestamp")]
+    timestamp: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    media_id: Option<String>,
+    /// When the tracker saw the request; the timeout counts from here.
+    #[serde(with = "timestamp")]
+    seen_at: DateTime<Utc>,
+}
+
+/// A `correlation_*` document the output didn't accept yet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Unsent {
+    event_name: String,
+    event_id: String,
+    json: String,
+    #[serde(with = "timestamp")]
+    timestamp: DateTime<Utc>,
+    /// Handed to the output, waiting for it to accept the document.
+    #[serde(skip)]
+    in_flight: bool,
+}
+
+/// The contents of the state file.
+#[derive(Serialize, Deserialize, Default)]
+struct State {
+    pending: Vec<PendingRequest>,
+    #[serde(default)]
+    unsent: Vec<Unsent>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct CorrelationCompleted<'a> {
+    event_id: String,
+    event_name: &'static str,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    event_timestamp: DateTime<Utc>,
+    correlation_id: &'a str,
+    request_event_name: &'a str,
+    response_event_name: &'a str,
+    request_event_id: &'a str,
+    response_event_id: &'a str,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    request_timestamp: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    response_timestamp: DateTime<Utc>,
+    duration_ms: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    media_id: Option<&'a str>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct CorrelationTimedOut<'a> {
+    event_id: String,
+    event_name: &'static str,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    event_timestamp: DateTime<Utc>,
+    correlation_id: &'a str,
+    request_event_name: &'a str,
+    expected_event_name: &'a str,
+    request_event_id: &'a str,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    request_timestamp: DateTime<Utc>,
+    timeout_ms: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    media_id: Option<&'a str>,
+}
+
+pub struct Tracker {
+    timeout: Duration,
+    state_file: Option<PathBuf>,
+    /// By request event name and correlation id.
+    pending: BTreeMap<(String, String), PendingRequest>,
+    /// By event id.
+    unsent: BTreeMap<String, Unsent>,
+    /// Whether `pending` or `unsent` changed since they were last saved.
+    dirty: bool,
+    saved_at: Instant,
+}
+
+impl Tracker {
+    pub fn new(timeout: Duration, state_file: Option<PathBuf>) -> Tracker {
+        Tracker {
+            timeout,
+            state_file,
+            pending: BTreeMap::new(),
+            unsent: BTreeMap::new(),
+            dirty: false,
+            saved_at: Instant::now(),
+        }
+    }
+
+    /// Set up the tracker, loading the pending requests from
+    /// `CORRELATION_STATE_FILE` if it exists.
+    pub fn from_config(config: &Config) -> io::Result<Tracker> {
+        let timeout_ms = i64::try_from(config.correlation_timeout_ms).ok()
+            .filter(|timeout_ms| *timeout_ms <= MAX_TIMEOUT_MS)
+            .ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("CORRELATION_TIMEOUT_MS {} is more than a year", config.correlation_timeout_ms),
+            ))?;
+        let state_file = setting(&config.correlation_state_file).map(PathBuf::from);
+        let mut tracker = Tracker::new(Duration::milliseconds(timeout_ms), state_file);
+        tracker.load()?;
+        Ok(tracker)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Track a transformed event: remember a request, or pair a response
+    /// with its request into a `correlation_completed` document. The
+    /// document is also kept until accepted, see `unsent`.
+    pub fn observe(&mut self, event: &Correlated) -> Option<Document> {
+        if PAIRS.iter().any(|(request, _)| *request == event.event_name) {
+            let key = (event.event_name.clone(), event.correlation_id.clone());
+            // A redelivered request keeps counting from when it was first seen
+            if self.pending.get(&key).map(|pending| &pending.event_id) != Some(&event.event_id) {
+                self.pending.insert(key, PendingRequest {
+                    event_name: event.event_name.clone(),
+                    correlation_id: event.correlation_id.clone(),
+                    event_id: event.event_id.clone(),
+                    timestamp: event.timestamp,
+                    media_id: event.media_id.clone(),
+                    seen_at: Utc::now(),
+                });
+                self.dirty = true;
+            }
+            return None;
+        }
+        let (request_name, _) = PAIRS.iter().find(|(_, response)| *response == event.event_name)?;
+        let request = match self.pending.remove(&(request_name.to_string(), event.correlation_id.clone())) {
+            Some(request) => request,
+            None => {
+                debug!("No pending {} for {} {}", request_name, event.event_name, event.correlation_id);
+                return None;
+            },
+        };
+        self.dirty = true;
+        let completed = CorrelationCompleted {
+            event_id: sha256(&["correlation_completed", &request.event_id, &event.event_id]),
+            event_name: "correlation_completed",
+            event_timestamp: event.timestamp,
+            correlation_id: &event.correlation_id,
+            request_event_name: &request.event_name,
+            response_event_name: &event.event_name,
+            request_event_id: &request.event_id,
+            response_event_id: &event.event_id,
+            request_timestamp: request.timestamp,
+            response_timestamp: event.timestamp,
+            duration_ms: (event.timestamp - request.timestamp).num_milliseconds(),
+            media_id: request.media_id.as_deref().or(event.media_id.as_deref()),
+        };
+        Some(self.emit("correlation_completed", derived(completed.event_id.clone(), event.timestamp, &completed)))
+    }
+
+    /// Give up on the requests seen longer than the timeout ago, with a
+    /// `correlation_timed_out` document each, also kept until accepted.
+    pub fn expire(&mut self, now: DateTime<Utc>) -> Vec<Document> {
+        let timeout = self.timeout;
+        let expired: Vec<(String, String)> = self.pending.iter()
+            .filter(|(_, request)| request.seen_at + timeout <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut documents = Vec::with_capacity(expired.len());
+        for key in expired {
+            let request = self.pending.remove(&key).expect("expired request is pending");
+            let expected = PAIRS.iter()
+                .find(|(name, _)| *name == request.event_name)
+                .map_or("", |(_, response)| response);
+            let timed_out = CorrelationTimedOut {
+                event_id: sha256(&["correlation_timed_out", &request.event_id]),
+                event_name: "correlation_timed_out",
+                event_timestamp: now,
+                correlation_id: &request.correlation_id,
+                request_event_name: &request.event_name,
+                expected_event_name: expected,
+                request_event_id: &request.event_id,
+                request_timestamp: request.timestamp,
+                timeout_ms: timeout.num_milliseconds(),
+                media_id: request.media_id.as_deref(),
+            };
+            warn!("No {} for {} {} within {} ms", expected, request.event_name, request.correlation_id,
+                timeout.num_milliseconds());
+            let document = derived(timed_out.event_id.clone(), now, &timed_out);
+            documents.push(self.emit("correlation_timed_out", document));
+        }
+        documents
+    }
+
+    fn emit(&mut self, event_name: &str, document: Document) -> Document {
+        self.unsent.insert(document.event_id.clone(), Unsent {
+            event_name: event_name.to_string(),
+            event_id: document.event_id.clone(),
+            json: document.json.clone(),
+            timestamp: document.timestamp,
+            in_flight: false,
+        });
+        self.dirty = true;
+        document
+    }
+
+    /// The documents to hand to the output (with their event name): those
+    /// not accepted yet, nor waiting for it to accept them.
+    pub fn unsent(&mut self) -> Vec<(String, Document)> {
+        self.unsent.values_mut()
+            .filter(|unsent| !unsent.in_flight)
+            .map(|unsent| {
+                unsent.in_flight = true;
+                let document = Document {
+                    event_id: unsent.event_id.clone(),
+                    json: unsent.json.clone(),
+                    timestamp: unsent.timestamp,
+                    correlation: None,
+                };
+                (unsent.event_name.clone(), document)
+            })
+            .collect()
+    }
+
+    /// The output accepted the document (or refused it for good): forget it.
+    pub fn accepted(&mut self, event_id: &str) {
+        if self.unsent.remove(event_id).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    /// The output didn't accept the document: `unsent` returns it again.
+    pub fn failed(&mut self, event_id: &str) {
+        if let Some(unsent) = self.unsent.get_mut(event_id) {
+            unsent.in_flight = false;
+        }
+    }
+
+    /// Stop waiting for the documents handed to the output, eg. after the
+    /// connection was lost: `unsent` returns them again.
+    pub fn retry_in_flight(&mut self) {
+        for unsent in self.unsent.values_mut() {
+            unsent.in_flight = false;
+        }
+    }
+
+    fn load(&mut self) -> io::Result<()> {
+        let path = match &self.state_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let state: State = serde_json::from_str(&contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+        info!("Loaded {} pending requests from {}", state.pending.len(), path.display());
+        self.pending = state.pending.into_iter()
+            .map(|request| ((request.event_name.clone(), request.correlation_id.clone()), request))
+            .collect();
+        self.unsent = state.unsent.into_iter().map(|unsent| (unsent.event_id.clone(), unsent)).collect();
+        Ok(())
+    }
+
+    /// Write the pending requests and unsent documents to the state file, if
+    /// they changed. The file is replaced by a synced temporary one, so a
+    /// crash leaves either the old or the new state.
+    pub fn save(&mut self) -> io::Result<()> {
+        self.saved_at = Instant::now();
+        let path = match &self.state_file {
+            Some(path) if self.dirty => path,
+            _ => return Ok(()),
+        };
+        let state = State {
+            pending: self.pending.values().cloned().collect(),
+            unsent: self.unsent.values().cloned().collect(),
+        };
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(&serde_json::to_vec(&state)?)?;
+        file.sync_all()?;
+        fs::rename(&temporary, path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// `save`, unless the state was saved less than `SAVE_INTERVAL` ago.
+    pub fn save_if_due(&mut self) -> io::Result<()> {
+        if self.saved_at.elapsed() < SAVE_INTERVAL {
+            return Ok(());
+        }
+        self.save()
+    }
+}
+
+fn derived<T: Serialize>(event_id: String, timestamp: DateTime<Utc>, event: &T) -> Document {
+    let json = serde_json::to_string(event).expect("derived documents serialize");
+    Document { event_id, json, timestamp, correlation: None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+    use chrono::TimeZone;
+    use serde_json::Value;
+
+    fn event(name: &str, event_id: &str, timestamp: DateTime<Utc>) -> Correlated {
+        Correlated {
+            event_name: name.to_string(),
+            correlation_id: "a1b2c3d4".to_string(),
+            event_id: event_id.to_string(),
+            timestamp,
+            media_id: if name.ends_with("Request") { Some("AB00112233".to_string()) } else { None },
+        }
+    }
+
+    fn json(document: &Document) -> Value {
+        serde_json::from_str(&document.json).unwrap()
+    }
+
+    #[test]
+    fn test_completed() {
+        let sent = Utc.ymd(2021, 2, 3).and_hms_milli(19, 21, 2, 32);
+        let mut tracker = Tracker::new(Duration::minutes(5), None);
+        assert_eq!(tracker.observe(&event("triggerExportRequest", "req", sent)), None);
+        assert_eq!(tracker.len(), 1);
+
+        let answered = sent + Duration::milliseconds(1500);
+        let document = tracker.observe(&event("triggerExportResponse", "resp", answered)).unwrap();
+        let completed = json(&document);
+        assert_eq!(completed["event_id"], document.event_id.as_str());
+        assert_eq!(completed["event_name"], "correlation_completed");
+        assert_eq!(completed["correlation_id"], "a1b2c3d4");
+        assert_eq!(completed["request_event_name"], "triggerExportRequest");
+        assert_eq!(completed["response_event_id"], "resp");
+        assert_eq!(completed["request_timestamp"], "2021-02-03T19:21:02.032Z");
+        assert_eq!(completed["duration_ms"], 1500);
+        assert_eq!(completed["media_id"], "AB00112233");
+        assert!(tracker.is_empty());
+
+        // Once paired, a redelivered response has nothing to pair with
+        assert_eq!(tracker.observe(&event("triggerExportResponse", "resp", answered)), None);
+    }
+
+    #[test]
+    fn test_unrelated_events() {
+        let mut tracker = Tracker::new(Duration::minutes(5), None);
+        assert_eq!(tracker.observe(&event("getMetadataResponse", "resp", Utc::now())), None);
+        assert_eq!(tracker.observe(&event("makeSubtitleAvailableRequest", "req", Utc::now())), None);
+        // A response of the other pair doesn't answer the request
+        tracker.observe(&event("getMetadataRequest", "req", Utc::now()));
+        assert_eq!(tracker.observe(&event("triggerExportResponse", "resp", Utc::now())), None);
+        assert_eq!(tracker.len(), 1);
+    }
+
+    #[test]
+    fn test_timed_out() {
+        let mut tracker = Tracker::new(Duration::minutes(5), None);
+        tracker.observe(&event("getMetadataRequest", "req", Utc::now()));
+        assert!(tracker.expire(Utc::now()).is_empty());
+
+        let documents = tracker.expire(Utc::now() + Duration::minutes(6));
+        assert_eq!(documents.len(), 1);
+        let timed_out = json(&documents[0]);
+        assert_eq!(timed_out["event_name"], "correlation_timed_out");
+        assert_eq!(timed_out["expected_event_name"], "getMetadataResponse");
+        assert_eq!(timed_out["request_event_id"], "req");
+        assert_eq!(timed_out["timeout_ms"], 300_000);
+        assert!(tracker.is_empty());
+    }
+
+    #[test]
+    fn test_unsent_until_accepted() {
+        let mut tracker = Tracker::new(Duration::minutes(5), None);
+        tracker.observe(&event("getMetadataRequest", "req", Utc::now()));
+        let timed_out = tracker.expire(Utc::now() + Duration::minutes(6));
+        let unsent = tracker.unsent();
+        assert_eq!(unsent.len(), 1);
+        assert_eq!(unsent[0].0, "correlation_timed_out");
+        assert_eq!(unsent[0].1, timed_out[0]);
+        // In flight: not handed out twice
+        assert!(tracker.unsent().is_empty());
+
+        tracker.failed(&timed_out[0].event_id);
+        assert_eq!(tracker.unsent().len(), 1);
+        // The connection was lost
+        tracker.retry_in_flight();
+        assert_eq!(tracker.unsent().len(), 1);
+        tracker.accepted(&timed_out[0].event_id);
+        tracker.retry_in_flight();
+        assert!(tracker.unsent().is_empty());
+    }
+
+    #[test]
+    fn test_state_file() {
+        let path = std::env::temp_dir().join(format!("amqp2elastic-correlation-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut tracker = Tracker::new(Duration::minutes(5), Some(path.clone()));
+        tracker.load().unwrap();
+        tracker.observe(&event("triggerExportRequest", "req", Utc::now()));
+        // Not yet: the tracker was just created
+        tracker.save_if_due().unwrap();
+        assert!(!path.exists());
+        tracker.save().unwrap();
+
+        let mut restarted = Tracker::new(Duration::minutes(5), Some(path.clone()));
+        restarted.load().unwrap();
+        assert_eq!(restarted.pending, tracker.pending);
+        let completed = restarted.observe(&event("triggerExportResponse", "resp", Utc::now())).unwrap();
+        restarted.save().unwrap();
+        let mut emptied = Tracker::new(Duration::minutes(5), Some(path.clone()));
+        emptied.load().unwrap();
+        assert!(emptied.is_empty());
+        // The completed document was not sent yet
+        assert_eq!(emptied.unsent(), vec![("correlation_completed".to_string(), completed.clone())]);
+        emptied.accepted(&completed.event_id);
+        emptied.save().unwrap();
+        let mut sent = Tracker::new(Duration::minutes(5), Some(path.clone()));
+        sent.load().unwrap();
+        assert!(sent.unsent().is_empty());
+
+        fs::write(&path, "{").unwrap();
+        assert!(Tracker::new(Duration::minutes(5), Some(path.clone())).load().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_timeout_from_config() {
+        let tracker = Tracker::from_config(&test_config(vec![("CORRELATION_TIMEOUT_MS", "60000")])).unwrap();
+        assert_eq!(tracker.timeout, Duration::minutes(1));
+        let too_long = test_config(vec![("CORRELATION_TIMEOUT_MS", &u64::MAX.to_string())]);
+        assert!(Tracker::from_config(&too_long).is_err());
+    }
+}
@@ -0,0 +1,355 @@
+//! Output sink writing events straight to Elasticsearch via the `_bulk` API.
+
+use std::fmt;
+use std::mem;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::prelude::*;
+use serde::Deserialize;
+
+use crate::{Config, Document};
+
+/// Errors for a bulk request as a whole (as opposed to per-item failures).
+#[derive(Debug)]
+pub enum ElasticError {
+    /// Elasticsearch could not be reached.
+    Transport(String),
+    /// Elasticsearch answered with a non-success HTTP status.
+    Status(u16, String),
+    /// The response body could not be understood.
+    InvalidResponse(String),
+    /// `ES_INDEX_PATTERN` is not a valid `strftime` pattern.
+    InvalidIndexPattern(String),
+}
+
+impl fmt::Display for ElasticError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElasticError::Transport(msg) => write!(f, "Elasticsearch unreachable: {}", msg),
+            ElasticError::Status(status, body) => {
+                write!(f, "Elasticsearch returned HTTP {}: {}", status, body)
+            },
+            ElasticError::InvalidResponse(msg) => {
+                write!(f, "Invalid response from Elasticsearch: {}", msg)
+            },
+            ElasticError::InvalidIndexPattern(pattern) => {
+                write!(f, "Invalid ES_INDEX_PATTERN {}: not a strftime pattern", pattern)
+            },
+        }
+    }
+}
+
+impl std::error::Error for ElasticError {}
+
+/// Check that `pattern` is a valid `strftime` pattern: formatting a date
+/// with an invalid one panics.
+pub fn check_index_pattern(pattern: &str) -> Result<(), ElasticError> {
+    if StrftimeItems::new(pattern).any(|item| item == Item::Error) {
+        return Err(ElasticError::InvalidIndexPattern(pattern.to_string()));
+    }
+    Ok(())
+}
+
+/// Outcome of indexing a single document.
+#[derive(Debug, PartialEq)]
+pub enum ItemStatus {
+    Indexed,
+    /// Failed, but may succeed when retried (eg. 429 or 5xx).
+    Retry(String),
+    /// Failed permanently (eg. a mapping conflict).
+    Rejected(String),
+}
+
+impl ItemStatus {
+    fn from_status(status: u16, error: Option<serde_json::Value>) -> ItemStatus {
+        let reason = || match &error {
+            Some(error) => error.to_string(),
+            None => format!("HTTP {}", status),
+        };
+        match status {
+            200..=299 => ItemStatus::Indexed,
+            429 | 500..=599 => ItemStatus::Retry(reason()),
+            _ => ItemStatus::Rejected(reason()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct BulkResponse {
+    items: Vec<BulkResponseItem>,
+}
+
+#[derive(Deserialize)]
+struct BulkResponseItem {
+    index: BulkItemResult,
+}
+
+#[derive(Deserialize)]
+struct BulkItemResult {
+    status: u16,
+    error: Option<serde_json::Value>,
+}
+
+/// Client for the Elasticsearch `_bulk` endpoint.
+pub struct BulkIndexer {
+    agent: ureq::Agent,
+    url: String,
+    index_pattern: String,
+}
+
+impl BulkIndexer {
+    /// `url` is the base URL of the cluster, optionally with credentials
+    /// (eg. `https://user:passwd@es:9200`). `index_pattern` is a `strftime`
+    /// pattern such as `vrt-events-%Y.%m`.
+    pub fn new(url: &str, index_pattern: &str) -> Result<BulkIndexer, ElasticError> {
+        check_index_pattern(index_pattern)?;
+        let mut agent = ureq::AgentBuilder::new().timeout(Duration::from_secs(30));
+        if url.starts_with("https://") {
+            match native_tls::TlsConnector::new() {
+                Ok(connector) => agent = agent.tls_connector(Arc::new(connector)),
+                Err(e) => warn!("Could not set up TLS for Elasticsearch: {}", e),
+            }
+        }
+        Ok(BulkIndexer {
+            agent: agent.build(),
+            url: url.trim_end_matches('/').to_string(),
+            index_pattern: index_pattern.to_string(),
+        })
+    }
+
+    /// The index of a document with the given event timestamp.
+    pub fn index_name(&self, at: DateTime<Utc>) -> String {
+        at.format(&self.index_pattern).to_string()
+    }
+
+    /// Build the NDJSON body of a bulk request. The documents are indexed
+    /// with their `event_id` as `_id`, into the index of their event
+    /// timestamp, so indexing one twice (even much later) overwrites it.
+    pub fn bulk_body(&self, docs: &[Document]) -> String {
+        let mut body = String::new();
+        for doc in docs {
+            let index = self.index_name(doc.timestamp);
+            let action = serde_json::json!({ "index": { "_index": index, "_id": doc.event_id } });
+            body.push_str(&action.to_string());
+            body.push('\n');
+            body.push_str(&doc.json);
+            body.push('\n');
+        }
+        body
+    }
+
+    /// Index the given JSON documents, returning a status per document, in
+    /// the same order.
+    pub fn send(&self, docs: &[Document]) -> Result<Vec<ItemStatus>, ElasticError> {
+        if docs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let body = self.bulk_body(docs);
+        let response = self.agent.post(&format!("{}/_bulk", self.url))
+            .set("Content-Type", "application/x-ndjson")
+            .send_string(&body);
+        let response = match response {
+            Ok(response) => response,
+            Err(ureq::Error::Status(status, response)) => {
+                let body = response.into_string().unwrap_or_default();
+                return Err(ElasticError::Status(status, body));
+            },
+            Err(e) => return Err(ElasticError::Transport(e.to_string())),
+        };
+        let body = response.into_string()
+            .map_err(|e| ElasticError::InvalidResponse(e.to_string()))?;
+        let bulk: BulkResponse = serde_json::from_str(&body)
+            .map_err(|e| ElasticError::InvalidResponse(e.to_string()))?;
+        if bulk.items.len() != docs.len() {
+            return Err(ElasticError::InvalidResponse(format!(
+                "expected {} items, got {}", docs.len(), bulk.items.len()
+            )));
+        }
+        Ok(bulk.items.into_iter()
+            .map(|item| ItemStatus::from_status(item.index.status, item.index.error))
+            .collect())
+    }
+}
+
+/// Buffers documents, each with a tag (eg. the AMQP delivery it came from),
+/// and sends them in bulk once enough are collected or the oldest one has
+/// waited long enough.
+pub struct BulkSink<T> {
+    indexer: BulkIndexer,
+    max_size: usize,
+    max_wait: Duration,
+    batch: Vec<(T, Document)>,
+    batch_started: Option<Instant>,
+}
+
+impl<T> BulkSink<T> {
+    pub fn new(indexer: BulkIndexer, max_size: usize, max_wait: Duration) -> BulkSink<T> {
+        BulkSink {
+            indexer,
+            max_size: max_size.max(1),
+            max_wait,
+            batch: Vec::new(),
+            batch_started: None,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Result<BulkSink<T>, ElasticError> {
+        Ok(BulkSink::new(
+            BulkIndexer::new(&config.es_url, &config.es_index_pattern)?,
+            config.es_bulk_size,
+            Duration::from_millis(config.es_bulk_interval_ms),
+        ))
+    }
+
+    pub fn push(&mut self, tag: T, doc: Document) {
+        if self.batch.is_empty() {
+            self.batch_started = Some(Instant::now());
+        }
+        self.batch.push((tag, doc));
+    }
+
+    pub fn len(&self) -> usize {
+        self.batch.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batch.is_empty()
+    }
+
+    /// Whether the batch should be flushed now.
+    pub fn is_due(&self) -> bool {
+        self.batch.len() >= self.max_size || self.time_until_due() == Duration::from_secs(0)
+    }
+
+    /// How long until the current batch is due because of its age.
+    pub fn time_until_due(&self) -> Duration {
+        match self.batch_started {
+            Some(started) => self.max_wait.checked_sub(started.elapsed()).unwrap_or_default(),
+            None => self.max_wait,
+        }
+    }
+
+    /// Send the current batch. Every tag is returned with the status of its
+    /// document; if the request as a whole fails, all are marked for retry.
+    pub fn flush(&mut self) -> Vec<(T, ItemStatus)> {
+        let batch = mem::take(&mut self.batch);
+        self.batch_started = None;
+        let (tags, docs): (Vec<T>, Vec<Document>) = batch.into_iter().unzip();
+        match self.indexer.send(&docs) {
+            Ok(statuses) => tags.into_iter().zip(statuses).collect(),
+            Err(e) => {
+                warn!("Bulk request of {} documents failed: {}", docs.len(), e);
+                let reason = e.to_string();
+                tags.into_iter().map(|tag| (tag, ItemStatus::Retry(reason.clone()))).collect()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn doc(event_id: &str, json: &str) -> Document {
+        let timestamp = Utc.ymd(2021, 2, 3).and_hms(20, 21, 2);
+        Document { event_id: event_id.to_string(), json: json.to_string(), timestamp, correlation: None }
+    }
+
+    /// Serve a single HTTP request with the given status and body, and
+    /// return the base URL plus a receiver for the request body.
+    fn stub_server(status: u16, response: &'static str) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                let lower = line.to_lowercase();
+                if let Some(value) = lower.strip_prefix("content-length:") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            tx.send(String::from_utf8(body).unwrap()).unwrap();
+            let mut stream = reader.into_inner();
+            write!(
+                stream,
+                "HTTP/1.1 {} OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status, response.len(), response
+            ).unwrap();
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn test_index_name() {
+        let indexer = BulkIndexer::new("http://localhost:9200", "vrt-events-%Y.%m").unwrap();
+        let at = Utc.ymd(2021, 2, 3).and_hms(20, 21, 2);
+        assert_eq!(indexer.index_name(at), "vrt-events-2021.02");
+        assert!(BulkIndexer::new("http://localhost:9200", "vrt-events-%Q").is_err());
+        assert!(check_index_pattern("vrt-events-%").is_err());
+    }
+
+    #[test]
+    fn test_bulk_body() {
+        let indexer = BulkIndexer::new("http://localhost:9200", "vrt-events-%Y.%m").unwrap();
+        // Redelivered a month later: still the index of the event
+        let mut late = doc("id-b", r#"{"b":2}"#);
+        late.timestamp = Utc.ymd(2021, 1, 31).and_hms(23, 59, 59);
+        let body = indexer.bulk_body(&[doc("id-a", r#"{"a":1}"#), late]);
+        assert_eq!(body, concat!(
+            "{\"index\":{\"_id\":\"id-a\",\"_index\":\"vrt-events-2021.02\"}}\n{\"a\":1}\n",
+            "{\"index\":{\"_id\":\"id-b\",\"_index\":\"vrt-events-2021.01\"}}\n{\"b\":2}\n",
+        ));
+    }
+
+    #[test]
+    fn test_flush_per_item_status() {
+        let (url, requests) = stub_server(200, r#"{"took":3,"errors":true,"items":[
+            {"index":{"_index":"vrt-events","status":201}},
+            {"index":{"_index":"vrt-events","status":400,"error":{"type":"mapper_parsing_exception"}}},
+            {"index":{"_index":"vrt-events","status":429,"error":{"type":"es_rejected_execution_exception"}}}
+        ]}"#);
+        let mut sink = BulkSink::new(BulkIndexer::new(&url, "vrt-events").unwrap(), 3, Duration::from_secs(60));
+        sink.push(1, doc("1", r#"{"a":1}"#));
+        sink.push(2, doc("2", r#"{"b":2}"#));
+        assert!(!sink.is_due());
+        sink.push(3, doc("3", r#"{"c":3}"#));
+        assert!(sink.is_due());
+
+        let results = sink.flush();
+
+        assert!(sink.is_empty());
+        assert_eq!(requests.recv().unwrap().lines().count(), 6);
+        assert_eq!(results[0], (1, ItemStatus::Indexed));
+        assert!(matches!(results[1], (2, ItemStatus::Rejected(_))));
+        assert!(matches!(results[2], (3, ItemStatus::Retry(_))));
+    }
+
+    #[test]
+    fn test_flush_request_failure_retries_all() {
+        let (url, _requests) = stub_server(503, r#"{"error":"unavailable"}"#);
+        let mut sink = BulkSink::new(BulkIndexer::new(&url, "vrt-events").unwrap(), 10, Duration::from_secs(60));
+        sink.push("a", doc("a", "{}"));
+        sink.push("b", doc("b", "{}"));
+
+        let results = sink.flush();
+
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|(_, status)| matches!(status, ItemStatus::Retry(_))));
+    }
+}
@@ -0,0 +1,140 @@
+//! Error envelopes: rejected messages wrapped in a JSON document with the
+//! reason, published to `AMQP_ERROR_QUEUE` so they can be indexed and
+//! searched like the events themselves.
+
+use amiquip::{AmqpValue, Delivery, FieldTable};
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::timestamp;
+
+#[derive(Serialize, Debug)]
+pub struct ErrorEnvelope {
+    pub event_name: &'static str,
+    pub error_kind: String,
+    pub error_message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root_tag: Option<String>,
+    pub exchange: String,
+    pub routing_key: String,
+    pub headers: Map<String, Value>,
+    /// The message body, lossily decoded if it is not valid UTF-8.
+    pub original_body: String,
+    pub service: &'static str,
+    pub service_version: &'static str,
+    pub timestamp: String,
+}
+
+impl ErrorEnvelope {
+    pub fn new(
+        body: &[u8],
+        exchange: &str,
+        routing_key: &str,
+        headers: Option<&FieldTable>,
+        error_kind: &str,
+        error_message: &str,
+    ) -> ErrorEnvelope {
+        ErrorEnvelope {
+            event_name: "error",
+            error_kind: error_kind.to_string(),
+            error_message: error_message.to_string(),
+            root_tag: None,
+            exchange: exchange.to_string(),
+            routing_key: routing_key.to_string(),
+            headers: headers.map(table_to_json).unwrap_or_default(),
+            original_body: String::from_utf8_lossy(body).into_owned(),
+            service: env!("CARGO_PKG_NAME"),
+            service_version: env!("CARGO_PKG_VERSION"),
+            timestamp: timestamp::format(&Utc::now()),
+        }
+    }
+
+    pub fn for_delivery(delivery: &Delivery, error_kind: &str, error_message: &str) -> ErrorEnvelope {
+        ErrorEnvelope::new(
+            &delivery.body,
+            &delivery.exchange,
+            &delivery.routing_key,
+            delivery.properties.headers().as_ref(),
+            error_kind,
+            error_message,
+        )
+    }
+
+    /// The root tag of the message, if it got as far as being parsed.
+    pub fn root_tag(mut self, root_tag: &str) -> ErrorEnvelope {
+        if !root_tag.is_empty() {
+            self.root_tag = Some(root_tag.to_string());
+        }
+        self
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+}
+
+/// Plain JSON for an AMQP header value (serde's representation of
+/// `AmqpValue` is tagged with the AMQP type).
+pub fn header_value(value: &AmqpValue) -> Value {
+    match value {
+        AmqpValue::Boolean(b) => Value::from(*b),
+        AmqpValue::ShortShortInt(n) => Value::from(*n),
+        AmqpValue::ShortShortUInt(n) => Value::from(*n),
+        AmqpValue::ShortInt(n) => Value::from(*n),
+        AmqpValue::ShortUInt(n) => Value::from(*n),
+        AmqpValue::LongInt(n) => Value::from(*n),
+        AmqpValue::LongUInt(n) => Value::from(*n),
+        AmqpValue::LongLongInt(n) => Value::from(*n),
+        AmqpValue::Float(n) => Value::from(*n),
+        AmqpValue::Double(n) => Value::from(*n),
+        AmqpValue::DecimalValue(d) => Value::from(f64::from(d.value) / 10f64.powi(i32::from(d.scale))),
+        AmqpValue::LongString(s) => Value::from(s.as_str()),
+        AmqpValue::FieldArray(values) => Value::Array(values.iter().map(header_value).collect()),
+        AmqpValue::Timestamp(t) => Value::from(*t),
+        AmqpValue::FieldTable(table) => Value::Object(table_to_json(table)),
+        AmqpValue::ByteArray(bytes) => Value::from(String::from_utf8_lossy(bytes).into_owned()),
+        AmqpValue::Void => Value::Null,
+    }
+}
+
+pub fn table_to_json(table: &FieldTable) -> Map<String, Value> {
+    table.iter().map(|(key, value)| (key.clone(), header_value(value))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope_json() {
+        let mut headers = FieldTable::new();
+        headers.insert("x-death".to_string(), AmqpValue::FieldArray(vec![AmqpValue::FieldTable({
+            let mut death = FieldTable::new();
+            death.insert("count".to_string(), AmqpValue::LongLongInt(2));
+            death.insert("queue".to_string(), AmqpValue::LongString("vrt2elk_events_xml_q".to_string()));
+            death
+        })]));
+        let envelope = ErrorEnvelope::new(
+            b"<unknownEvent/>", "vrt", "vrt.events", Some(&headers),
+            "unknown_root_tag", "Unknown event type: unknownEvent",
+        ).root_tag("unknownEvent");
+
+        let json: Value = serde_json::from_str(&envelope.to_json().unwrap()).unwrap();
+
+        assert_eq!(json["error_kind"], "unknown_root_tag");
+        assert_eq!(json["root_tag"], "unknownEvent");
+        assert_eq!(json["original_body"], "<unknownEvent/>");
+        assert_eq!(json["routing_key"], "vrt.events");
+        assert_eq!(json["headers"]["x-death"][0]["count"], 2);
+        assert_eq!(json["service_version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn test_invalid_utf8_body() {
+        let envelope = ErrorEnvelope::new(b"<a>\xff</a>", "", "q", None, "invalid_utf8", "Invalid UTF-8");
+        assert_eq!(envelope.original_body, "<a>\u{fffd}</a>");
+        assert!(envelope.headers.is_empty());
+        assert!(envelope.root_tag.is_none());
+    }
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+use std::str::Utf8Error;
+
+use xmltree::ParseError;
+
+use crate::timestamp::INVALID_TIMESTAMP;
+
+/// Everything that can go wrong while transforming an XML message to JSON.
+#[derive(Debug)]
+pub enum TransformError {
+    /// The message is not well-formed XML, or does not match the event
+    /// structure.
+    MalformedXml(String),
+    /// A field required by the event type is absent from the message.
+    MissingField { event: String, field: String },
+    /// The event's timestamp is not in any of the known formats; `value` is
+    /// the timestamp as sent.
+    InvalidTimestamp { event: String, value: String },
+    /// The root tag does not correspond to a registered event type.
+    UnknownRootTag(String),
+    /// The message body is not valid UTF-8.
+    InvalidUtf8(Utf8Error),
+    /// The event could not be serialized to JSON.
+    Serialization(serde_json::Error),
+    /// The document does not match the JSON Schema of its event type.
+    SchemaViolation { event: String, errors: Vec<String> },
+}
+
+impl TransformError {
+    /// Short, stable name of the kind of error, eg. for error envelopes.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TransformError::MalformedXml(_) => "malformed_xml",
+            TransformError::MissingField { .. } => "missing_field",
+            TransformError::InvalidTimestamp { .. } => "invalid_timestamp",
+            TransformError::UnknownRootTag(_) => "unknown_root_tag",
+            TransformError::InvalidUtf8(_) => "invalid_utf8",
+            TransformError::Serialization(_) => "serialization",
+            TransformError::SchemaViolation { .. } => "schema_violation",
+        }
+    }
+
+    /// Classify an error from `serde_xml_rs` while deserializing `event`.
+    pub fn from_xml_error(event: &str, err: serde_xml_rs::Error) -> TransformError {
+        if let serde_xml_rs::ErrorKind::Custom(msg) = err.kind() {
+            // serde reports missing fields as "missing field `<name>`"
+            if let Some(field) = msg.strip_prefix("missing field `") {
+                return TransformError::MissingField {
+                    event: event.to_string(),
+                    field: field.trim_end_matches('`').to_string(),
+                };
+            }
+            if let Some(value) = msg.strip_prefix(INVALID_TIMESTAMP) {
+                return TransformError::InvalidTimestamp {
+                    event: event.to_string(),
+                    value: value.strip_suffix('`').unwrap_or(value).to_string(),
+                };
+            }
+        }
+        TransformError::MalformedXml(format!("{}: {}", event, err))
+    }
+}
+
+impl fmt::Display for TransformError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransformError::MalformedXml(msg) => write!(f, "Malformed XML: {}", msg),
+            TransformError::MissingField { event, field } => {
+                write!(f, "Missing required field `{}` in {}", field, event)
+            },
+            TransformError::InvalidTimestamp { event, value } => {
+                write!(f, "Invalid timestamp `{}` in {}", value, event)
+            },
+            TransformError::UnknownRootTag(tag) => write!(f, "Unknown event type: {}", tag),
+            TransformError::InvalidUtf8(err) => write!(f, "Invalid UTF-8: {}", err),
+            TransformError::Serialization(err) => write!(f, "Serialization failed: {}", err),
+            TransformError::SchemaViolation { event, errors } => {
+                write!(f, "{} does not match its schema: {}", event, errors.join("; "))
+            },
+        }
+    }
+}
+
+impl std::error::Error for TransformError {}
+
+impl From<ParseError> for TransformError {
+    fn from(err: ParseError) -> TransformError {
+        TransformError::MalformedXml(err.to_string())
+    }
+}
+
+impl From<Utf8Error> for TransformError {
+    fn from(err: Utf8Error) -> TransformError {
+        TransformError::InvalidUtf8(err)
+    }
+}
+
+impl From<serde_json::Error> for TransformError {
+    fn from(err: serde_json::Error) -> TransformError {
+        TransformError::Serialization(err)
+    }
+}
@@ -0,0 +1,109 @@
+//! Stable `event_id`s for the emitted documents, so a message that is
+//! delivered twice (eg. after a crash between publishing and acking) yields
+//! the same document id, and the index can deduplicate on it.
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::VrtEvent;
+
+/// How the `event_id` of a document is derived.
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum EventIdStrategy {
+    /// SHA-256 of the raw message body.
+    Body,
+    /// The AMQP `message_id` of the delivery, or the body hash if it has
+    /// none.
+    MessageId,
+    /// SHA-256 of the correlation id and event name, or the body hash if
+    /// the event has no correlation id. Not the timestamp: some event types
+    /// default it to the time they are handled.
+    Correlation,
+}
+
+impl EventIdStrategy {
+    pub fn event_id(self, body: &str, message_id: Option<&str>, event: &dyn VrtEvent) -> String {
+        match self {
+            EventIdStrategy::Body => sha256(&[body]),
+            EventIdStrategy::MessageId => match message_id.filter(|id| !id.is_empty()) {
+                Some(message_id) => message_id.to_string(),
+                None => sha256(&[body]),
+            },
+            EventIdStrategy::Correlation => match event.correlation_id().filter(|id| !id.is_empty()) {
+                Some(correlation_id) => sha256(&[correlation_id, event.name()]),
+                None => sha256(&[body]),
+            },
+        }
+    }
+}
+
+/// Hex SHA-256 of the parts, separated by newlines.
+pub(crate) fn sha256(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            hasher.update(b"\n");
+        }
+        hasher.update(part.as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GetMetadataResponse, TriggerExportRequest};
+    use std::thread;
+    use std::time::Duration;
+    use xmltree::Element;
+
+    const BODY: &str = r##"<triggerExportRequest>
+  <timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <correlationId>a1b2c3d4</correlationId>
+  <mediaId>AB00112233</mediaId>
+</triggerExportRequest>"##;
+
+    fn event(body: &str) -> TriggerExportRequest {
+        TriggerExportRequest::parse(&Element::parse(body.as_bytes()).unwrap(), body).unwrap()
+    }
+
+    #[test]
+    fn test_body_hash() {
+        let id = EventIdStrategy::Body.event_id(BODY, Some("msg-1"), &event(BODY));
+        assert_eq!(id.len(), 64);
+        assert_eq!(id, EventIdStrategy::Body.event_id(BODY, None, &event(BODY)));
+        assert_eq!(sha256(&["abc"]), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn test_message_id() {
+        let strategy = EventIdStrategy::MessageId;
+        assert_eq!(strategy.event_id(BODY, Some("msg-1"), &event(BODY)), "msg-1");
+        assert_eq!(strategy.event_id(BODY, None, &event(BODY)), sha256(&[BODY]));
+    }
+
+    #[test]
+    fn test_correlation_without_timestamp() {
+        let body = r##"<getMetadataResponse>
+  <correlationId>a1b2c3d4</correlationId>
+  <status>SUCCESS</status>
+</getMetadataResponse>"##;
+        let event = || GetMetadataResponse::parse(&Element::parse(body.as_bytes()).unwrap(), body).unwrap();
+        let strategy = EventIdStrategy::Correlation;
+        let id = strategy.event_id(body, None, &event());
+        // Handled again later, with another default timestamp
+        thread::sleep(Duration::from_millis(2));
+        assert_eq!(id, strategy.event_id(body, None, &event()));
+    }
+
+    #[test]
+    fn test_correlation() {
+        let strategy = EventIdStrategy::Correlation;
+        // The same event sent with other whitespace and timestamp notation
+        let other = BODY.replace("\n  ", "").replace("20:21:02.032+01:00", "19:21:02.032Z");
+        let id = strategy.event_id(BODY, None, &event(BODY));
+        assert_eq!(id, strategy.event_id(&other, None, &event(&other)));
+        assert_ne!(id, sha256(&[BODY]));
+    }
+}
@@ -0,0 +1,234 @@
+//! Generic transformation of messages of an unknown event type, so they can
+//! be indexed before the event type is registered (`UNKNOWN_EVENTS=generic`).
+//!
+//! Every child element becomes a field, named by its (dot-separated, snake
+//! case) element path; attributes are kept as `<path>@<attribute>`. Repeated
+//! fields become arrays. The document is flagged with `event_known: false`.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::Value;
+use xmltree::{Element, XMLNode};
+
+use crate::{timestamp, Origin, TransformError, VrtEvent};
+
+/// Fields set on every document, that an element (or a mapped field) can't
+/// overwrite.
+pub(crate) const RESERVED: &[&str] = &[
+    "event_id", "event_name", "event_namespace", "event_known", "event_timestamp", "event_handle_timestamp",
+    "event_payload", "origin", "schema_errors",
+];
+
+/// What the events whose fields are taken from the XML, rather than
+/// declared, have in common: generic ones and those of the mapping file. The
+/// first arm adds the accessors of the fields, `@methods` the `VrtEvent`
+/// methods besides `parse`.
+macro_rules! xml_event {
+    ($event:ident) => {
+        impl $event {
+            pub fn fields(&self) -> &BTreeMap<String, Value> {
+                &self.fields
+            }
+
+            fn field(&self, name: &str) -> Option<&str> {
+                self.fields.get(name).and_then(Value::as_str)
+            }
+        }
+    };
+    (@methods) => {
+        fn name(&self) -> &str {
+            &self.event_name
+        }
+
+        fn origin(&self) -> Origin {
+            Origin::from(self.origin.as_str())
+        }
+
+        fn set_origin(&mut self, origin: Origin) {
+            self.origin = origin.to_str();
+        }
+
+        fn timestamp(&self) -> DateTime<Utc> {
+            self.event_timestamp
+        }
+
+        fn to_json(&self) -> Result<String, TransformError> {
+            Ok(serde_json::to_string(self)?)
+        }
+
+        fn correlation_id(&self) -> Option<&str> {
+            self.field("correlation_id")
+        }
+
+        fn media_id(&self) -> Option<&str> {
+            self.field("media_id")
+        }
+    };
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct GenericEvent {
+    event_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event_namespace: Option<String>,
+    event_known: bool,
+    /// The `<timestamp>` of the message if it has a valid one, otherwise the
+    /// time it was handled.
+    #[serde(with = "timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    event_timestamp: DateTime<Utc>,
+    event_handle_timestamp: String,
+    #[serde(flatten)]
+    fields: BTreeMap<String, Value>,
+    event_payload: String,
+    origin: String,
+}
+
+xml_event!(GenericEvent);
+
+impl VrtEvent for GenericEvent {
+    fn parse(xml: &Element, body: &str) -> Result<GenericEvent, TransformError> {
+        let mut fields = BTreeMap::new();
+        add_attributes(&mut fields, "", xml);
+        for child in xml.children.iter().filter_map(XMLNode::as_element) {
+            add_element(&mut fields, &snake_case(&child.name), child);
+        }
+        for name in RESERVED {
+            if let Some(value) = fields.remove(*name) {
+                fields.insert(format!("xml_{}", name), value);
+            }
+        }
+        let event_handle_timestamp = Utc::now();
+        let event_timestamp = fields.get("timestamp")
+            .and_then(Value::as_str)
+            .and_then(timestamp::parse)
+            .unwrap_or(event_handle_timestamp);
+        Ok(GenericEvent {
+            event_name: xml.name.clone(),
+            event_namespace: xml.namespace.clone(),
+            event_known: false,
+            event_timestamp,
+            event_handle_timestamp: event_handle_timestamp.to_rfc3339(),
+            fields,
+            event_payload: body.to_string(),
+            // Unknown event types are assumed to be new VRT events, unless
+            // the origin rules say otherwise
+            origin: Origin::Vrt.to_str(),
+        })
+    }
+
+    xml_event!(@methods);
+}
+
+fn add_element(fields: &mut BTreeMap<String, Value>, path: &str, element: &Element) {
+    add_attributes(fields, path, element);
+    let children: Vec<&Element> = element.children.iter()
+        .filter_map(XMLNode::as_element)
+        .collect();
+    if children.is_empty() {
+        let text = element.get_text().map(|text| text.trim().to_string()).unwrap_or_default();
+        add_value(fields, path, Value::String(text));
+        return;
+    }
+    for child in children {
+        add_element(fields, &format!("{}.{}", path, snake_case(&child.name)), child);
+    }
+}
+
+fn add_attributes(fields: &mut BTreeMap<String, Value>, path: &str, element: &Element) {
+    for (name, value) in &element.attributes {
+        // Namespace declarations are not data
+        if name == "xmlns" || name.starts_with("xmlns:") {
+            continue;
+        }
+        add_value(fields, &format!("{}@{}", path, name), Value::String(value.clone()));
+    }
+}
+
+fn add_value(fields: &mut BTreeMap<String, Value>, path: &str, value: Value) {
+    match fields.get_mut(path) {
+        Some(Value::Array(values)) => values.push(value),
+        Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+        None => {
+            fields.insert(path.to_string(), value);
+        },
+    }
+}
+
+/// `mediaId` to `media_id`, like the fields of the registered event types.
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (i, c) in chars.iter().enumerate() {
+        if c.is_uppercase() {
+            // A new word: after a lowercase letter or digit, or the last
+            // capital of an acronym (`OTType`)
+            let previous = if i > 0 { Some(chars[i - 1]) } else { None };
+            let next_lower = matches!(chars.get(i + 1), Some(next) if next.is_lowercase());
+            match previous {
+                Some(p) if p.is_lowercase() || p.is_ascii_digit() => snake.push('_'),
+                Some(p) if p.is_uppercase() && next_lower => snake.push('_'),
+                _ => {},
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(if *c == '-' { '_' } else { *c });
+        }
+    }
+    snake
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_generic_event() {
+        let body = r##"<newSubtitleEvent version="2">
+  <timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <mediaId>AB00112233</mediaId>
+  <subtitle language="nl"><path>/subs/AB00112233.srt</path></subtitle>
+  <subtitle language="en"><path>/subs/AB00112233_en.srt</path></subtitle>
+  <origin>broadcaster</origin>
+  <schemaErrors>none</schemaErrors>
+</newSubtitleEvent>"##;
+        let xml = Element::parse(body.as_bytes()).unwrap();
+
+        let event = GenericEvent::parse(&xml, body).unwrap();
+        let json: Value = serde_json::from_str(&event.to_json().unwrap()).unwrap();
+
+        assert_eq!(event.name(), "newSubtitleEvent");
+        assert_eq!(event.media_id(), Some("AB00112233"));
+        assert_eq!(json["event_known"], false);
+        assert_eq!(json["event_timestamp"], "2021-02-03T19:21:02.032Z");
+        assert_eq!(json["@version"], "2");
+        assert_eq!(json["subtitle@language"], json!(["nl", "en"]));
+        assert_eq!(json["subtitle.path"], json!(["/subs/AB00112233.srt", "/subs/AB00112233_en.srt"]));
+        assert_eq!(json["origin"], "vrt");
+        assert_eq!(json["xml_origin"], "broadcaster");
+        assert_eq!(json["xml_schema_errors"], "none");
+        assert_eq!(json["event_payload"], body);
+    }
+
+    #[test]
+    fn test_generic_event_without_timestamp() {
+        let body = "<pingEvent><sentAt>yesterday</sentAt></pingEvent>";
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        let before = Utc::now();
+        let event = GenericEvent::parse(&xml, body).unwrap();
+        assert!(event.timestamp() >= before);
+        assert_eq!(event.fields()["sent_at"], "yesterday");
+    }
+
+    #[test]
+    fn test_snake_case() {
+        assert_eq!(snake_case("mediaId"), "media_id");
+        assert_eq!(snake_case("s3bucket"), "s3bucket");
+        assert_eq!(snake_case("OTType"), "ot_type");
+        assert_eq!(snake_case("subtitle-path"), "subtitle_path");
+    }
+}
@@ -0,0 +1,134 @@
+//! Liveness and readiness, served on `/healthz` and `/readyz` by the
+//! `server` module.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::supervisor::ConnectionState;
+
+/// While consuming, the consumer loop wakes up at least once a minute; if it
+/// hasn't for this long, it is stuck.
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(180);
+
+lazy_static! {
+    static ref HEALTH: Health = Health::new();
+}
+
+/// Snapshot of the health of the consumer.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Status {
+    /// The process is making progress: not stuck in the consumer loop.
+    pub live: bool,
+    /// Connected, consuming from the input queue and able to publish.
+    pub ready: bool,
+    pub connection: String,
+    pub consuming: bool,
+    pub output_ready: bool,
+}
+
+struct State {
+    connection: ConnectionState,
+    output_ready: bool,
+    heartbeat: Instant,
+}
+
+pub struct Health {
+    state: Mutex<State>,
+}
+
+impl Health {
+    pub fn new() -> Health {
+        Health {
+            state: Mutex::new(State {
+                connection: ConnectionState::Disconnected,
+                output_ready: false,
+                heartbeat: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn set_connection_state(&self, connection: ConnectionState) {
+        let mut state = self.state.lock().unwrap();
+        state.connection = connection;
+        state.heartbeat = Instant::now();
+    }
+
+    /// Whether the output (the broker, or Elasticsearch) accepts documents.
+    pub fn set_output_ready(&self, ready: bool) {
+        self.state.lock().unwrap().output_ready = ready;
+    }
+
+    /// Record that the consumer loop is still turning.
+    pub fn heartbeat(&self) {
+        self.state.lock().unwrap().heartbeat = Instant::now();
+    }
+
+    pub fn status(&self) -> Status {
+        let state = self.state.lock().unwrap();
+        let consuming = state.connection == ConnectionState::Consuming;
+        Status {
+            live: !(consuming && state.heartbeat.elapsed() > STALL_TIMEOUT),
+            ready: consuming && state.output_ready,
+            connection: format!("{:?}", state.connection).to_lowercase(),
+            consuming,
+            output_ready: state.output_ready,
+        }
+    }
+}
+
+impl Default for Health {
+    fn default() -> Health {
+        Health::new()
+    }
+}
+
+pub fn set_connection_state(connection: ConnectionState) {
+    HEALTH.set_connection_state(connection);
+}
+
+pub fn set_output_ready(ready: bool) {
+    HEALTH.set_output_ready(ready);
+}
+
+pub fn heartbeat() {
+    HEALTH.heartbeat();
+}
+
+pub fn status() -> Status {
+    HEALTH.status()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ready_when_consuming_and_output_ready() {
+        let health = Health::new();
+        assert!(health.status().live);
+        assert!(!health.status().ready);
+
+        health.set_connection_state(ConnectionState::Consuming);
+        assert!(!health.status().ready);
+        health.set_output_ready(true);
+        assert!(health.status().ready);
+
+        health.set_connection_state(ConnectionState::Disconnected);
+        let status = health.status();
+        assert!(status.live && !status.ready);
+        assert_eq!(status.connection, "disconnected");
+    }
+
+    #[test]
+    fn test_stalled_consumer_is_not_live() {
+        let health = Health::new();
+        health.set_connection_state(ConnectionState::Consuming);
+        health.state.lock().unwrap().heartbeat -= STALL_TIMEOUT + Duration::from_secs(1);
+        assert!(!health.status().live);
+        health.heartbeat();
+        assert!(health.status().live);
+    }
+}
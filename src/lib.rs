@@ -1,8 +1,11 @@
+use std::error::Error;
+use std::path::Path;
 use std::str;
+use std::sync::Arc;
 
+use amiquip::{AmqpProperties, Publish};
+use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
-use serde_json;
-use serde_xml_rs;
 use xmltree::Element;
 use chrono::prelude::*;
 
@@ -10,6 +13,43 @@ use chrono::prelude::*;
 extern crate log;
 extern crate serde_derive;
 
+pub mod supervisor;
+pub mod tls;
+pub mod confirms;
+pub mod elastic;
+mod error;
+pub mod metadata;
+pub mod ot;
+pub mod timestamp;
+pub mod metrics;
+pub mod health;
+pub mod convert;
+pub mod connection;
+pub mod replay;
+pub mod server;
+pub mod envelope;
+pub mod redelivery;
+#[macro_use]
+pub mod generic;
+pub mod mapping;
+pub mod event_id;
+pub mod correlation;
+pub mod template;
+pub mod config_file;
+pub mod schema;
+pub mod origin;
+
+pub use correlation::{Correlated, Tracker};
+pub use error::TransformError;
+pub use event_id::EventIdStrategy;
+pub use generic::GenericEvent;
+pub use config_file::ConfigFileError;
+pub use mapping::{MappedEvent, Mapping};
+pub use metadata::Metadata;
+pub use origin::{OriginRules, Source};
+pub use schema::{SchemaValidation, Validator};
+pub use ot::OtInfo;
+
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
@@ -19,12 +59,175 @@ pub struct Config {
     pub amqp_passwd: String,
     #[serde(default="default_amqp_host")]
     pub amqp_host: String,
-    #[serde(default="default_amqp_port")]
-    pub amqp_port: String,
+    /// 5672, or 5671 for `amqps`, if not given.
+    pub amqp_port: Option<String>,
     #[serde(default="default_amqp_vhost")]
     pub amqp_vhost: String,
     #[serde(default="default_amqp_prefetch_count")]
     pub amqp_prefetch_count: u16,
+    #[serde(default="default_amqp_scheme")]
+    pub amqp_scheme: AmqpScheme,
+    /// Extra CA bundle (PEM) to trust for `amqps`.
+    pub amqp_tls_ca_file: Option<String>,
+    /// Client certificate (PEM) for `amqps`.
+    pub amqp_tls_cert_file: Option<String>,
+    /// Private key (PEM, PKCS#8) of the client certificate.
+    pub amqp_tls_key_file: Option<String>,
+    #[serde(default="default_true")]
+    pub amqp_tls_verify_hostname: bool,
+    #[serde(default="default_amqp_in_queue")]
+    pub amqp_in_queue: String,
+    #[serde(default="default_amqp_out_queue")]
+    pub amqp_out_queue: String,
+    /// Exchange to publish to; the default exchange if empty.
+    #[serde(default)]
+    pub amqp_out_exchange: String,
+    #[serde(default="default_amqp_out_exchange_type")]
+    pub amqp_out_exchange_type: ExchangeKind,
+    /// Routing key to publish with; defaults to the output queue name.
+    pub amqp_routing_key: Option<String>,
+    /// Actively declare the queues, exchange and binding on startup instead
+    /// of expecting them to exist.
+    #[serde(default)]
+    pub amqp_declare: bool,
+    /// Dead-letter queue of the input queue: set up with `AMQP_DECLARE`, and
+    /// read by the `replay` subcommand.
+    pub amqp_dlq: Option<String>,
+    /// Queue to move messages to that still fail on `replay`.
+    pub amqp_parking_queue: Option<String>,
+    /// Queue to publish error envelopes of rejected messages to; disabled
+    /// if empty.
+    pub amqp_error_queue: Option<String>,
+    /// How long to wait for the broker to confirm a published message.
+    #[serde(default="default_amqp_confirm_timeout_ms")]
+    pub amqp_confirm_timeout_ms: u64,
+    /// Requeue deliveries whose published message was nacked or not
+    /// confirmed in time; if false, they are rejected to the DLX.
+    #[serde(default="default_true")]
+    pub amqp_confirm_requeue: bool,
+    /// Delays before redelivering a message that failed for a transient
+    /// reason, via a retry queue per delay; the last one is repeated.
+    #[serde(default="default_amqp_redelivery_delays_ms")]
+    pub amqp_redelivery_delays_ms: Vec<u64>,
+    /// Delayed redeliveries before dead-lettering the message; 0 disables
+    /// them (transient failures are requeued right away).
+    #[serde(default)]
+    pub amqp_redelivery_max_attempts: u32,
+    #[serde(default="default_amqp_retry_initial_ms")]
+    pub amqp_retry_initial_ms: u64,
+    #[serde(default="default_amqp_retry_max_ms")]
+    pub amqp_retry_max_ms: u64,
+    #[serde(default="default_amqp_retry_multiplier")]
+    pub amqp_retry_multiplier: f64,
+    /// Consecutive failed (re)connection attempts before giving up; 0 means
+    /// retry forever.
+    #[serde(default)]
+    pub amqp_retry_max_attempts: u32,
+    /// Reject messages of an unknown event type, or index them as generic
+    /// documents.
+    #[serde(default="default_unknown_events")]
+    pub unknown_events: UnknownEvents,
+    /// YAML or TOML file declaring event types by configuration, see the
+    /// `mapping` module.
+    pub mapping_file: Option<String>,
+    /// YAML or TOML file with the rules deciding the origin of messages, see
+    /// the `origin` module.
+    pub origin_rules_file: Option<String>,
+    /// Namespace URIs of the built-in event types (besides no namespace);
+    /// empty for any namespace.
+    #[serde(default)]
+    pub xml_namespaces: Vec<String>,
+    /// How to derive the `event_id` of the documents.
+    #[serde(default="default_event_id_strategy")]
+    pub event_id_strategy: EventIdStrategy,
+    /// Check the documents against their JSON Schema, and flag or reject
+    /// those that don't match; see the `schema` module.
+    #[serde(default="default_schema_validation")]
+    pub schema_validation: SchemaValidation,
+    /// Directory with published `<event>.schema.json` files to validate
+    /// against, instead of the generated schemas.
+    pub schema_dir: Option<String>,
+    /// Pair requests with their responses, see the `correlation` module.
+    #[serde(default)]
+    pub correlation_tracking: bool,
+    /// How long a request may stay unanswered before it is reported as
+    /// timed out; at most a year.
+    #[serde(default="default_correlation_timeout_ms")]
+    pub correlation_timeout_ms: u64,
+    /// File to keep the pending requests in across restarts; in memory only
+    /// if empty.
+    pub correlation_state_file: Option<String>,
+    #[serde(default="default_output")]
+    pub output: Output,
+    #[serde(default="default_es_url")]
+    pub es_url: String,
+    #[serde(default="default_es_index_pattern")]
+    pub es_index_pattern: String,
+    #[serde(default="default_es_bulk_size")]
+    pub es_bulk_size: usize,
+    #[serde(default="default_es_bulk_interval_ms")]
+    pub es_bulk_interval_ms: u64,
+    /// On SIGTERM/SIGINT, how long to wait for the deliveries in flight to
+    /// be published and acked before closing the connection.
+    #[serde(default="default_shutdown_timeout_ms")]
+    pub shutdown_timeout_ms: u64,
+    /// Address to serve `/metrics`, `/healthz` and `/readyz` on; disabled if
+    /// empty.
+    #[serde(default="default_http_listen_addr")]
+    pub http_listen_addr: String,
+}
+
+impl Config {
+    /// The routing key for published events.
+    pub fn routing_key(&self) -> &str {
+        setting(&self.amqp_routing_key).unwrap_or(&self.amqp_out_queue)
+    }
+
+    /// The broker port: the IANA port of the scheme unless `AMQP_PORT` is set.
+    pub fn amqp_port(&self) -> &str {
+        setting(&self.amqp_port).unwrap_or(match self.amqp_scheme {
+            AmqpScheme::Amqp => "5672",
+            AmqpScheme::Amqps => "5671",
+        })
+    }
+}
+
+/// Treat empty settings (as in `.env.example`) as unset.
+pub fn setting(value: &Option<String>) -> Option<&str> {
+    value.as_deref().filter(|value| !value.is_empty())
+}
+
+/// The configuration from the given environment variables, for tests.
+#[cfg(test)]
+pub(crate) fn test_config(env: Vec<(&str, &str)>) -> Config {
+    envy::from_iter(env.into_iter().map(|(k, v)| (k.to_string(), v.to_string()))).unwrap()
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum AmqpScheme {
+    Amqp,
+    Amqps,
+}
+
+/// Type of the output exchange, when declared on startup.
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ExchangeKind {
+    Direct,
+    Topic,
+    Fanout,
+    Headers,
+}
+
+/// Where the transformed JSON events are sent to.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    /// Publish to the output queue (and on to ELK via Logstash).
+    Amqp,
+    /// Index directly into Elasticsearch via the `_bulk` API.
+    Elasticsearch,
 }
 
 fn default_amqp_up() -> String  {
@@ -35,10 +238,6 @@ fn default_amqp_host() -> String  {
   String::from("localhost")
 }
 
-fn default_amqp_port() -> String  {
-  String::from("5672")
-}
-
 fn default_amqp_vhost() -> String  {
   String::from("")
 }
@@ -47,6 +246,90 @@ fn default_amqp_prefetch_count() -> u16  {
   100
 }
 
+fn default_amqp_scheme() -> AmqpScheme  {
+  AmqpScheme::Amqp
+}
+
+fn default_true() -> bool  {
+  true
+}
+
+fn default_amqp_in_queue() -> String  {
+  String::from("vrt2elk_events_xml_q")
+}
+
+fn default_amqp_out_queue() -> String  {
+  String::from("vrt2elk_events_json_q")
+}
+
+fn default_amqp_out_exchange_type() -> ExchangeKind  {
+  ExchangeKind::Direct
+}
+
+fn default_amqp_confirm_timeout_ms() -> u64  {
+  10000
+}
+
+fn default_amqp_redelivery_delays_ms() -> Vec<u64>  {
+  vec![5000, 60000, 600000]
+}
+
+fn default_amqp_retry_initial_ms() -> u64  {
+  1000
+}
+
+fn default_amqp_retry_max_ms() -> u64  {
+  60000
+}
+
+fn default_amqp_retry_multiplier() -> f64  {
+  2.0
+}
+
+fn default_unknown_events() -> UnknownEvents  {
+  UnknownEvents::Reject
+}
+
+fn default_event_id_strategy() -> EventIdStrategy  {
+  EventIdStrategy::Body
+}
+
+fn default_schema_validation() -> SchemaValidation  {
+  SchemaValidation::Off
+}
+
+fn default_correlation_timeout_ms() -> u64  {
+  3600000
+}
+
+fn default_output() -> Output  {
+  Output::Amqp
+}
+
+fn default_es_url() -> String  {
+  String::from("http://localhost:9200")
+}
+
+fn default_es_index_pattern() -> String  {
+  String::from("vrt-events-%Y.%m")
+}
+
+fn default_es_bulk_size() -> usize  {
+  100
+}
+
+fn default_es_bulk_interval_ms() -> u64  {
+  1000
+}
+
+fn default_shutdown_timeout_ms() -> u64  {
+  20000
+}
+
+fn default_http_listen_addr() -> String  {
+  String::from("0.0.0.0:8080")
+}
+
 fn default_file() -> String  {
   String::from("n/a")
 }
@@ -56,15 +339,17 @@ fn default_media_id() -> String  {
 }
 
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct EssenceArchivedEvent {
     #[serde(skip_deserializing)]
     event_name: String,
-    #[serde(alias = "timestamp")]
-    event_timestamp: String,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    event_namespace: Option<String>,
+    #[serde(alias = "timestamp", with = "timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    event_timestamp: DateTime<Utc>,
     #[serde(skip_deserializing)]
     event_handle_timestamp: String,
-    //~ event_handle_time: DateTime<Utc>,   // doesn't seem to work, but it should: no compromises
     file: String,
     pid: String,
     md5sum: String,
@@ -76,12 +361,15 @@ pub struct EssenceArchivedEvent {
     origin: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct EssenceLinkedEvent {
     #[serde(skip_deserializing)]
     event_name: String,
-    #[serde(alias = "timestamp")]
-    event_timestamp: String,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    event_namespace: Option<String>,
+    #[serde(alias = "timestamp", with = "timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    event_timestamp: DateTime<Utc>,
     #[serde(skip_deserializing)]
     event_handle_timestamp: String,
     file: String,
@@ -93,12 +381,15 @@ pub struct EssenceLinkedEvent {
     origin: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct EssenceUnlinkedEvent {
     #[serde(skip_deserializing)]
     event_name: String,
-    #[serde(alias = "timestamp")]
-    event_timestamp: String,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    event_namespace: Option<String>,
+    #[serde(alias = "timestamp", with = "timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    event_timestamp: DateTime<Utc>,
     #[serde(skip_deserializing)]
     event_handle_timestamp: String,
     #[serde(alias = "mediaId")]
@@ -109,12 +400,15 @@ pub struct EssenceUnlinkedEvent {
     origin: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct ObjectDeletedEvent {
     #[serde(skip_deserializing)]
     event_name: String,
-    #[serde(alias = "timestamp")]
-    event_timestamp: String,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    event_namespace: Option<String>,
+    #[serde(alias = "timestamp", with = "timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    event_timestamp: DateTime<Utc>,
     #[serde(skip_deserializing)]
     event_handle_timestamp: String,
     #[serde(alias = "mediaId")]
@@ -125,12 +419,15 @@ pub struct ObjectDeletedEvent {
     origin: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct GetMetadataRequest {
     #[serde(skip_deserializing)]
     event_name: String,
-    #[serde(alias = "timestamp")]
-    event_timestamp: String,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    event_namespace: Option<String>,
+    #[serde(alias = "timestamp", with = "timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    event_timestamp: DateTime<Utc>,
     #[serde(skip_deserializing)]
     event_handle_timestamp: String,
     #[serde(alias = "mediaId")]
@@ -143,80 +440,107 @@ pub struct GetMetadataRequest {
     origin: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct GetMetadataResponse {
     #[serde(skip_deserializing)]
     event_name: String,
-    #[serde(alias = "timestamp", default = "default_timestamp")]
-    event_timestamp: String,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    event_namespace: Option<String>,
+    #[serde(alias = "timestamp", with = "timestamp", default = "default_timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    event_timestamp: DateTime<Utc>,
     #[serde(skip_deserializing)]
     event_handle_timestamp: String,
     #[serde(alias = "correlationId")]
     correlation_id: String,
-    // TODO: metadata struct?
-    //~ metadata: String,
+    #[serde(skip_deserializing)]
+    metadata: Option<Metadata>,
     #[serde(skip_deserializing)]
     event_payload: String,
     #[serde(skip_deserializing)]
     origin: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct MetadataUpdatedEvent {
     #[serde(skip_deserializing)]
     event_name: String,
-    #[serde(alias = "timestamp")]
-    event_timestamp: String,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    event_namespace: Option<String>,
+    #[serde(alias = "timestamp", with = "timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    event_timestamp: DateTime<Utc>,
     #[serde(skip_deserializing)]
     event_handle_timestamp: String,
-    // TODO: metadata struct?
-    //~ metadata: String,
+    #[serde(skip_deserializing)]
+    metadata: Option<Metadata>,
     #[serde(skip_deserializing)]
     event_payload: String,
     #[serde(skip_deserializing)]
     origin: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct ClosedOtAvailableEvent {
     #[serde(skip_deserializing)]
     event_name: String,
-    #[serde(alias = "timestamp")]
-    event_timestamp: String,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    event_namespace: Option<String>,
+    #[serde(alias = "timestamp", with = "timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    event_timestamp: DateTime<Utc>,
     #[serde(skip_deserializing)]
     event_handle_timestamp: String,
     #[serde(skip_deserializing)]
     event_payload: String,
     #[serde(skip_deserializing)]
     origin: String,
-    // ? https://stackoverflow.com/questions/46753955/how-to-transform-fields-during-deserialization-using-serde
-    //~ #[serde(alias = "mediaId")]
-    //~ media_id: String,
+    // Nested in the message: extracted from the tree, see `OtInfo`
+    #[serde(skip_deserializing, default = "default_media_id")]
+    media_id: String,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    ot_type: Option<String>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    subtitle_path: Option<String>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    subtitle_format: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct OpenOtAvailableEvent {
     #[serde(skip_deserializing)]
     event_name: String,
-    #[serde(alias = "timestamp")]
-    event_timestamp: String,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    event_namespace: Option<String>,
+    #[serde(alias = "timestamp", with = "timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    event_timestamp: DateTime<Utc>,
     #[serde(skip_deserializing)]
     event_handle_timestamp: String,
     #[serde(skip_deserializing)]
     event_payload: String,
     #[serde(skip_deserializing)]
     origin: String,
-    // ? https://stackoverflow.com/questions/46753955/how-to-transform-fields-during-deserialization-using-serde
-    //~ #[serde(alias = "mediaId")]
-    //~ media_id: String,
+    // Nested in the message: extracted from the tree, see `OtInfo`
+    #[serde(skip_deserializing, default = "default_media_id")]
+    media_id: String,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    ot_type: Option<String>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    subtitle_path: Option<String>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    subtitle_format: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct MakeSubtitleAvailableRequest {
     #[serde(skip_deserializing)]
     event_name: String,
-    #[serde(alias = "timestamp", default = "default_timestamp")]
-    event_timestamp: String,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    event_namespace: Option<String>,
+    #[serde(alias = "timestamp", with = "timestamp", default = "default_timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    event_timestamp: DateTime<Utc>,
     #[serde(skip_deserializing)]
     event_handle_timestamp: String,
     #[serde(alias = "correlationId")]
@@ -233,12 +557,15 @@ pub struct MakeSubtitleAvailableRequest {
     origin: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct TriggerExportRequest {
     #[serde(skip_deserializing)]
     event_name: String,
-    #[serde(alias = "timestamp")]
-    event_timestamp: String,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    event_namespace: Option<String>,
+    #[serde(alias = "timestamp", with = "timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    event_timestamp: DateTime<Utc>,
     #[serde(skip_deserializing)]
     event_handle_timestamp: String,
     #[serde(alias = "mediaId", default = "default_media_id")]
@@ -253,12 +580,15 @@ pub struct TriggerExportRequest {
     origin: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct TriggerExportResponse {
     #[serde(skip_deserializing)]
     event_name: String,
-    #[serde(alias = "timestamp")]
-    event_timestamp: String,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    event_namespace: Option<String>,
+    #[serde(alias = "timestamp", with = "timestamp")]
+    #[schemars(with = "timestamp::Rfc3339")]
+    event_timestamp: DateTime<Utc>,
     #[serde(skip_deserializing)]
     event_handle_timestamp: String,
     #[serde(alias = "correlationId")]
@@ -269,277 +599,434 @@ pub struct TriggerExportResponse {
     origin: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-enum VrtEvent {
-    EssenceArchivedEvent,
-    EssenceLinkedEvent,
-    EssenceUnlinkedEvent,
-    ObjectDeletedEvent,
-    GetMetadataRequest,
-    GetMetadataResponse,
-    MetadataUpdatedEvent,
-    ClosedOtAvailableEvent,
-    OpenOtAvailableEvent,
-    MakeSubtitleAvailableRequest,
-    TriggerExportRequest,
-    TriggerExportResponse,
-}
-
-impl EssenceArchivedEvent {
-
-    pub fn new(xml: Element, body: &str) -> EssenceArchivedEvent {
-        // Deserialize XML to struct
-        let mut event: EssenceArchivedEvent = serde_xml_rs::from_str(body).unwrap();
-        // Add in other properties
-        event.event_name = String::from(&xml.name);
-        event.event_payload = body.to_string();
-        event.event_handle_timestamp = Utc::now().to_rfc3339();
-        event.origin = Origin::Meemoo.to_str();
-        return event
+/// Common behaviour of every VRT event type.
+///
+/// Implementations are generated by `vrt_events!` below: adding a new event
+/// type means declaring its struct and registering it there.
+pub trait VrtEvent: std::fmt::Debug {
+    /// Deserialize the XML `body` (with root element `xml`) into the event.
+    fn parse(xml: &Element, body: &str) -> Result<Self, TransformError> where Self: Sized;
+    /// The event name, ie. the root tag of the XML message.
+    fn name(&self) -> &str;
+    fn origin(&self) -> Origin;
+    /// Attribute the event to another origin, see the `origin` module.
+    fn set_origin(&mut self, origin: Origin);
+    /// When the event happened, according to the sender.
+    fn timestamp(&self) -> DateTime<Utc>;
+    fn to_json(&self) -> Result<String, TransformError>;
+    fn correlation_id(&self) -> Option<&str> {
+        None
     }
-
-    pub fn to_json(&self) -> String {
-        serde_json::to_string(self).unwrap()
+    fn media_id(&self) -> Option<&str> {
+        None
     }
-
 }
 
-impl EssenceLinkedEvent {
-
-    pub fn new(xml: Element, body: &str) -> EssenceLinkedEvent {
-        // Deserialize XML to struct
-        let mut event: EssenceLinkedEvent = serde_xml_rs::from_str(body).unwrap();
-        // Add in other properties
-        event.event_name = String::from(&xml.name);
-        event.event_payload = body.to_string();
-        event.event_handle_timestamp = Utc::now().to_rfc3339();
-        event.origin = Origin::Vrt.to_str();
-        return event
-    }
-
-    pub fn to_json(&self) -> String {
-        serde_json::to_string(self).unwrap()
-    }
-
+/// An entry in the event type registry.
+#[derive(Debug)]
+pub struct EventType {
+    pub root_tag: &'static str,
+    pub origin: Origin,
+    parse: fn(&Element, &str) -> Result<Event, TransformError>,
+    schema: fn(&str) -> serde_json::Value,
 }
 
-impl EssenceUnlinkedEvent {
-
-    pub fn new(xml: Element, body: &str) -> EssenceUnlinkedEvent {
-        // Deserialize XML to struct
-        let mut event: EssenceUnlinkedEvent = serde_xml_rs::from_str(body).unwrap();
-        // Add in other properties
-        event.event_name = String::from(&xml.name);
-        event.event_payload = body.to_string();
-        event.event_handle_timestamp = Utc::now().to_rfc3339();
-        event.origin = Origin::Vrt.to_str();
-        return event
+impl EventType {
+    /// Find the registered event type for the given XML root tag.
+    pub fn lookup(root_tag: &str) -> Option<&'static EventType> {
+        EVENT_TYPES.iter().find(|t| t.root_tag == root_tag)
     }
 
-    pub fn to_json(&self) -> String {
-        serde_json::to_string(self).unwrap()
-    }
-
-}
-
-impl ObjectDeletedEvent {
-
-    pub fn new(xml: Element, body: &str) -> ObjectDeletedEvent {
-        // Deserialize XML to struct
-        let mut event: ObjectDeletedEvent = serde_xml_rs::from_str(body).unwrap();
-        // Add in other properties
-        event.event_name = String::from(&xml.name);
-        event.event_payload = body.to_string();
-        event.event_handle_timestamp = Utc::now().to_rfc3339();
-        event.origin = Origin::Vrt.to_str();
-        return event
+    pub fn parse(&self, xml: &Element, body: &str) -> Result<Event, TransformError> {
+        (self.parse)(xml, body)
     }
 
-    pub fn to_json(&self) -> String {
-        serde_json::to_string(self).unwrap()
+    /// The JSON Schema of the documents of this event type.
+    pub fn schema(&self) -> serde_json::Value {
+        (self.schema)(self.root_tag)
     }
-
 }
 
-impl GetMetadataRequest {
-
-    pub fn new(xml: Element, body: &str) -> GetMetadataRequest {
-        // Deserialize XML to struct
-        let mut event: GetMetadataRequest = serde_xml_rs::from_str(body).unwrap();
-        // Add in other properties
-        event.event_name = String::from(&xml.name);
-        event.event_payload = body.to_string();
-        event.event_handle_timestamp = Utc::now().to_rfc3339();
-        event.origin = Origin::Meemoo.to_str();
-        return event
-    }
-
-    pub fn to_json(&self) -> String {
-        serde_json::to_string(self).unwrap()
-    }
-
+/// All supported VRT event types.
+pub fn event_types() -> &'static [EventType] {
+    EVENT_TYPES
 }
 
-impl GetMetadataResponse {
-
-    pub fn new(xml: Element, body: &str) -> GetMetadataResponse {
-        // Deserialize XML to struct
-        let mut event: GetMetadataResponse = serde_xml_rs::from_str(body).unwrap();
-        // Add in other properties
-        event.event_name = String::from(&xml.name);
-        event.event_payload = body.to_string();
-        event.event_handle_timestamp = Utc::now().to_rfc3339();
-        event.origin = Origin::Vrt.to_str();
-        return event
-    }
-
-    pub fn to_json(&self) -> String {
-        serde_json::to_string(self).unwrap()
-    }
-
+fn parse_as<E: VrtEvent + Into<Event>>(xml: &Element, body: &str) -> Result<Event, TransformError> {
+    E::parse(xml, body).map(Into::into)
 }
 
-impl MetadataUpdatedEvent {
+macro_rules! vrt_events {
+    ($( $event:ident { tag: $tag:literal, origin: $origin:ident $(, $id:ident)* } ),* $(,)?) => {
+        $(
+        impl VrtEvent for $event {
+
+            fn parse(xml: &Element, body: &str) -> Result<$event, TransformError> {
+                // Deserialize XML to struct
+                let mut event: $event = serde_xml_rs::from_str(body)
+                    .map_err(|e| TransformError::from_xml_error($tag, e))?;
+                // Add in other properties
+                event.event_name = String::from(&xml.name);
+                event.event_namespace = xml.namespace.clone();
+                event.event_payload = body.to_string();
+                event.event_handle_timestamp = Utc::now().to_rfc3339();
+                event.origin = Origin::$origin.to_str();
+                $( vrt_events!(@extract event, xml, $id); )*
+                Ok(event)
+            }
+
+            fn name(&self) -> &str {
+                &self.event_name
+            }
+
+            fn origin(&self) -> Origin {
+                Origin::from(self.origin.as_str())
+            }
+
+            fn set_origin(&mut self, origin: Origin) {
+                self.origin = origin.to_str();
+            }
+
+            fn timestamp(&self) -> DateTime<Utc> {
+                self.event_timestamp
+            }
+
+            fn to_json(&self) -> Result<String, TransformError> {
+                Ok(serde_json::to_string(self)?)
+            }
+
+            $( vrt_events!(@method $id); )*
+        }
 
-    pub fn new(xml: Element, body: &str) -> MetadataUpdatedEvent {
-        // Deserialize XML to struct
-        let mut event: MetadataUpdatedEvent = serde_xml_rs::from_str(body).unwrap();
-        // Add in other properties
-        event.event_name = String::from(&xml.name);
-        event.event_payload = body.to_string();
-        event.event_handle_timestamp = Utc::now().to_rfc3339();
-        event.origin = Origin::Vrt.to_str();
-        return event
-    }
+        impl From<$event> for Event {
+            fn from(event: $event) -> Event {
+                Event::$event(event)
+            }
+        }
+        )*
+
+        /// A parsed VRT event of any of the registered types.
+        #[derive(Serialize, Debug)]
+        #[serde(untagged)]
+        pub enum Event {
+            $( $event($event), )*
+            /// An event type declared in the mapping file.
+            Mapped(MappedEvent),
+            /// A message of an unknown event type, see the `generic` module.
+            Generic(GenericEvent),
+        }
 
-    pub fn to_json(&self) -> String {
-        serde_json::to_string(self).unwrap()
-    }
+        impl Event {
+            pub fn as_vrt_event(&self) -> &dyn VrtEvent {
+                match self {
+                    $( Event::$event(event) => event, )*
+                    Event::Mapped(event) => event,
+                    Event::Generic(event) => event,
+                }
+            }
+
+            pub fn as_vrt_event_mut(&mut self) -> &mut dyn VrtEvent {
+                match self {
+                    $( Event::$event(event) => event, )*
+                    Event::Mapped(event) => event,
+                    Event::Generic(event) => event,
+                }
+            }
+        }
 
+        static EVENT_TYPES: &[EventType] = &[
+            $( EventType {
+                root_tag: $tag,
+                origin: Origin::$origin,
+                parse: parse_as::<$event>,
+                schema: schema::event_schema::<$event>,
+            }, )*
+        ];
+    };
+    (@method correlation_id) => {
+        fn correlation_id(&self) -> Option<&str> {
+            Some(&self.correlation_id)
+        }
+    };
+    (@method media_id) => {
+        fn media_id(&self) -> Option<&str> {
+            Some(&self.media_id)
+        }
+    };
+    (@method $other:ident) => {};
+    // Fields that are not deserialized by serde, but extracted from the tree
+    (@extract $event:ident, $xml:ident, metadata) => {
+        $event.metadata = Metadata::from_event($xml);
+    };
+    (@extract $event:ident, $xml:ident, ot_info) => {
+        let ot = OtInfo::from_xml($xml);
+        if let Some(media_id) = ot.media_id {
+            $event.media_id = media_id;
+        }
+        $event.ot_type = ot.ot_type;
+        $event.subtitle_path = ot.subtitle_path;
+        $event.subtitle_format = ot.subtitle_format;
+    };
+    (@extract $event:ident, $xml:ident, $other:ident) => {};
 }
 
-impl ClosedOtAvailableEvent {
-
-    pub fn new(xml: Element, body: &str) -> ClosedOtAvailableEvent {
-        // Deserialize XML to struct
-        let mut event: ClosedOtAvailableEvent = serde_xml_rs::from_str(body).unwrap();
-        // Add in other properties
-        event.event_name = String::from(&xml.name);
-        event.event_payload = body.to_string();
-        event.event_handle_timestamp = Utc::now().to_rfc3339();
-        event.origin = Origin::Vrt.to_str();
-        return event
-    }
+vrt_events! {
+    EssenceArchivedEvent { tag: "essenceArchivedEvent", origin: Meemoo },
+    EssenceLinkedEvent { tag: "essenceLinkedEvent", origin: Vrt, media_id },
+    EssenceUnlinkedEvent { tag: "essenceUnlinkedEvent", origin: Vrt, media_id },
+    ObjectDeletedEvent { tag: "objectDeletedEvent", origin: Vrt, media_id },
+    GetMetadataRequest { tag: "getMetadataRequest", origin: Meemoo, media_id, correlation_id },
+    GetMetadataResponse { tag: "getMetadataResponse", origin: Vrt, correlation_id, metadata },
+    MetadataUpdatedEvent { tag: "metadataUpdatedEvent", origin: Vrt, metadata },
+    ClosedOtAvailableEvent { tag: "closedOtAvailableEvent", origin: Vrt, media_id, ot_info },
+    OpenOtAvailableEvent { tag: "openOtAvailableEvent", origin: Vrt, media_id, ot_info },
+    MakeSubtitleAvailableRequest { tag: "makeSubtitleAvailableRequest", origin: Meemoo, media_id, correlation_id },
+    TriggerExportRequest { tag: "triggerExportRequest", origin: Meemoo, media_id, correlation_id },
+    TriggerExportResponse { tag: "triggerExportResponse", origin: Vrt, correlation_id },
+}
 
-    pub fn to_json(&self) -> String {
-        serde_json::to_string(self).unwrap()
+impl Event {
+    /// Parse an XML message into the event type registered for its root tag.
+    pub fn from_xml(xml: &Element, body: &str) -> Result<Event, TransformError> {
+        match EventType::lookup(&xml.name) {
+            Some(event_type) => event_type.parse(xml, body),
+            None => Err(TransformError::UnknownRootTag(xml.name.clone())),
+        }
     }
-
 }
 
-impl OpenOtAvailableEvent {
-
-    pub fn new(xml: Element, body: &str) -> OpenOtAvailableEvent {
-        // Deserialize XML to struct
-        let mut event: OpenOtAvailableEvent = serde_xml_rs::from_str(body).unwrap();
-        // Add in other properties
-        event.event_name = String::from(&xml.name);
-        event.event_payload = body.to_string();
-        event.event_handle_timestamp = Utc::now().to_rfc3339();
-        event.origin = Origin::Vrt.to_str();
-        return event
-    }
+/// A transformed message, ready for the output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    /// Also the `message_id` when published, and the `_id` when indexed.
+    pub event_id: String,
+    pub json: String,
+    /// The event timestamp, which decides the Elasticsearch index.
+    pub timestamp: DateTime<Utc>,
+    /// For the correlation tracker, if the event has a correlation id.
+    pub correlation: Option<Correlated>,
+}
 
-    pub fn to_json(&self) -> String {
-        serde_json::to_string(self).unwrap()
+impl Document {
+    /// The message to publish the document with. It is mandatory: the broker
+    /// returns it, rather than dropping it, if no queue is bound to the key.
+    pub fn publish(&self, routing_key: &str) -> Publish<'_> {
+        let properties = AmqpProperties::default().with_message_id(self.event_id.clone());
+        Publish { mandatory: true, ..Publish::with_properties(self.json.as_bytes(), routing_key, properties) }
     }
-
 }
 
-impl MakeSubtitleAvailableRequest {
+#[derive(Serialize)]
+struct Identified<'a> {
+    event_id: &'a str,
+    #[serde(flatten)]
+    event: &'a Event,
+    /// How the document differs from its schema, with
+    /// `SCHEMA_VALIDATION=flag`.
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    schema_errors: &'a [String],
+}
 
-    pub fn new(xml: Element, body: &str) -> MakeSubtitleAvailableRequest {
-        // Deserialize XML to struct
-        let mut event: MakeSubtitleAvailableRequest = serde_xml_rs::from_str(body).unwrap();
-        // Add in other properties
-        event.event_name = String::from(&xml.name);
-        event.event_payload = body.to_string();
-        event.event_handle_timestamp = Utc::now().to_rfc3339();
-        event.origin = Origin::Meemoo.to_str();
-        return event
-    }
+/// What to do with messages whose root tag is not a registered event type.
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownEvents {
+    /// Reject them (to the DLX).
+    Reject,
+    /// Transform them into a generic document, see the `generic` module.
+    Generic,
+}
 
-    pub fn to_json(&self) -> String {
-        serde_json::to_string(self).unwrap()
+/// The name of an element with its namespace URI, in Clark notation:
+/// `{http://example.com/ns}localName`, or just `localName`.
+pub fn qualified_name(xml: &Element) -> String {
+    match &xml.namespace {
+        Some(namespace) => format!("{{{}}}{}", namespace, xml.name),
+        None => xml.name.clone(),
     }
+}
 
+/// Decode and parse a raw message body.
+pub fn parse_message(body: &[u8]) -> Result<(Element, &str), TransformError> {
+    let body = str::from_utf8(body)?;
+    let xml = Element::parse(body.as_bytes())?;
+    Ok((xml, body))
 }
 
-impl TriggerExportRequest {
+/// The transformation of parsed messages to the JSON documents we emit,
+/// shared by the consumer and the `convert` and `replay` subcommands.
+#[derive(Debug, Clone)]
+pub struct Transformer {
+    /// Event types declared in the mapping file; they take precedence over
+    /// the built-in ones.
+    pub mapping: Mapping,
+    /// Namespace URIs in which the built-in event types are recognized,
+    /// besides no namespace; empty for any namespace.
+    pub namespaces: Vec<String>,
+    pub unknown_events: UnknownEvents,
+    pub event_id: EventIdStrategy,
+    /// Checks the documents against their schema, unless
+    /// `SCHEMA_VALIDATION` is off.
+    pub validator: Option<Arc<Validator>>,
+    /// Who sent a message, when not the built-in origin of its event type.
+    pub origins: OriginRules,
+}
 
-    pub fn new(xml: Element, body: &str) -> TriggerExportRequest {
-        // Deserialize XML to struct
-        let mut event: TriggerExportRequest = serde_xml_rs::from_str(body).unwrap();
-        // Add in other properties
-        event.event_name = String::from(&xml.name);
-        event.event_payload = body.to_string();
-        event.event_handle_timestamp = Utc::now().to_rfc3339();
-        event.origin = Origin::Meemoo.to_str();
-        return event
+impl Transformer {
+    /// Set up the transformation, loading `MAPPING_FILE`,
+    /// `ORIGIN_RULES_FILE` and the schemas in `SCHEMA_DIR` if set.
+    pub fn from_config(config: &Config) -> Result<Transformer, Box<dyn Error>> {
+        let mapping = match setting(&config.mapping_file) {
+            Some(path) => Mapping::load(Path::new(path))?,
+            None => Mapping::default(),
+        };
+        let origins = match setting(&config.origin_rules_file) {
+            Some(path) => OriginRules::load(Path::new(path))?,
+            None => OriginRules::default(),
+        };
+        mapping.check_origins(&origins)?;
+        let mut transformer = Transformer {
+            mapping,
+            namespaces: config.xml_namespaces.iter().filter(|uri| !uri.is_empty()).cloned().collect(),
+            unknown_events: config.unknown_events,
+            event_id: config.event_id_strategy,
+            validator: None,
+            origins,
+        };
+        if config.schema_validation != SchemaValidation::Off {
+            let schema_dir = setting(&config.schema_dir);
+            let validator = Validator::from_config(config.schema_validation, schema_dir, &transformer)?;
+            transformer.validator = Some(Arc::new(validator));
+        }
+        Ok(transformer)
     }
 
-    pub fn to_json(&self) -> String {
-        serde_json::to_string(self).unwrap()
+    /// Parse a message into its event: mapped, built-in or generic. Event
+    /// types are found by the namespace and local name of the root element.
+    pub fn event(&self, xml: &Element, body: &str) -> Result<Event, TransformError> {
+        let namespace = xml.namespace.as_deref();
+        if let Some(event_mapping) = self.mapping.get(namespace, &xml.name) {
+            return event_mapping.parse(xml, body).map(Event::Mapped);
+        }
+        let built_in = if self.accepts_namespace(namespace) {
+            Event::from_xml(xml, body)
+        } else {
+            Err(TransformError::UnknownRootTag(qualified_name(xml)))
+        };
+        match built_in {
+            Err(TransformError::UnknownRootTag(_)) if self.unknown_events == UnknownEvents::Generic => {
+                info!("Unknown event type {}, transforming it generically", xml.name);
+                GenericEvent::parse(xml, body).map(Event::Generic)
+            },
+            result => result,
+        }
     }
 
-    pub fn correlation_id(&self) -> &str {
-        &self.correlation_id
+    /// The name of the schema of `event`, see `schema::schemas`.
+    fn schema_name(&self, xml: &Element, event: &Event) -> String {
+        match event {
+            Event::Generic(_) => schema::GENERIC.to_string(),
+            Event::Mapped(_) if self.mapping.events.contains_key(&qualified_name(xml)) => qualified_name(xml),
+            _ => xml.name.clone(),
+        }
     }
 
-}
-
-impl TriggerExportResponse {
-
-    pub fn new(xml: Element, body: &str) -> TriggerExportResponse {
-        // Deserialize XML to struct
-        let mut event: TriggerExportResponse = serde_xml_rs::from_str(body).unwrap();
-        // Add in other properties
-        event.event_name = String::from(&xml.name);
-        event.event_payload = body.to_string();
-        event.event_handle_timestamp = Utc::now().to_rfc3339();
-        event.origin = Origin::Vrt.to_str();
-        return event
+    fn accepts_namespace(&self, namespace: Option<&str>) -> bool {
+        match namespace {
+            Some(namespace) => self.namespaces.is_empty() || self.namespaces.iter().any(|uri| uri == namespace),
+            None => true,
+        }
     }
 
-    pub fn to_json(&self) -> String {
-        serde_json::to_string(self).unwrap()
+    /// Transform a parsed message from `source` to the JSON document we emit.
+    pub fn handle_xml(&self, xml: Element, body: &str, source: &Source) -> Result<Document, TransformError> {
+        debug!("{:#?}", xml);
+        let root_tag = String::from(&xml.name);
+        info!("Root tag is: {:#?}", root_tag);
+        let mut event = self.event(&xml, body)?;
+        if let Some(origin) = self.origins.origin(source, &root_tag) {
+            event.as_vrt_event_mut().set_origin(origin);
+        }
+        debug!("{:?}", event);
+        let event_id = self.event_id.event_id(body, source.message_id(), event.as_vrt_event());
+        // Serialize it to a JSON string, with the event_id first
+        let mut identified = Identified { event_id: &event_id, event: &event, schema_errors: &[] };
+        let mut json = serde_json::to_string(&identified)?;
+        if let Some(validator) = &self.validator {
+            let schema_errors = validator.validate(&self.schema_name(&xml, &event), &serde_json::from_str(&json)?);
+            if !schema_errors.is_empty() {
+                if validator.mode == SchemaValidation::Reject {
+                    return Err(TransformError::SchemaViolation { event: root_tag, errors: schema_errors });
+                }
+                warn!("{} does not match its schema: {}", root_tag, schema_errors.join("; "));
+                identified.schema_errors = &schema_errors;
+                json = serde_json::to_string(&identified)?;
+            }
+        }
+        let correlation = Correlated::of(event.as_vrt_event(), &event_id);
+        Ok(Document { event_id, json, timestamp: event.as_vrt_event().timestamp(), correlation })
     }
+}
 
-    pub fn correlation_id(&self) -> &str {
-        &self.correlation_id
+impl Default for Transformer {
+    fn default() -> Transformer {
+        Transformer {
+            mapping: Mapping::default(),
+            namespaces: Vec::new(),
+            unknown_events: UnknownEvents::Reject,
+            event_id: EventIdStrategy::Body,
+            validator: None,
+            origins: OriginRules::default(),
+        }
     }
-
 }
 
-#[derive(Debug, Serialize, PartialEq)]
+/// Who sent an event: VRT, meemoo, or another partner declared in
+/// `ORIGIN_RULES_FILE`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(from = "String", into = "String")]
 pub enum Origin {
     Vrt,
     Meemoo,
+    Partner(String),
 }
 
 impl Origin {
     pub fn to_str(&self) -> String {
-        match &*self {
+        match self {
             Origin::Vrt => "vrt".to_string(),
             Origin::Meemoo => "meemoo".to_string(),
+            Origin::Partner(name) => name.clone(),
+        }
+    }
+}
+
+impl From<&str> for Origin {
+    fn from(name: &str) -> Origin {
+        match name {
+            "vrt" => Origin::Vrt,
+            "meemoo" => Origin::Meemoo,
+            _ => Origin::Partner(name.to_string()),
         }
     }
 }
 
-fn default_timestamp() -> String {
+impl From<String> for Origin {
+    fn from(name: String) -> Origin {
+        Origin::from(name.as_str())
+    }
+}
+
+impl From<Origin> for String {
+    fn from(origin: Origin) -> String {
+        origin.to_str()
+    }
+}
+
+fn default_timestamp() -> DateTime<Utc> {
     // Issue bij VRT! -> VD-
     warn!("default_timestamp called!");
-    Utc::now().to_rfc3339()
+    Utc::now()
 }
 
 #[cfg(test)]
@@ -565,11 +1052,11 @@ mod tests {
 </triggerExportRequest>"##;
         let xml = Element::parse(body.as_bytes()).unwrap();
         // Act
-        let event = TriggerExportRequest::new(xml, body);
+        let event = TriggerExportRequest::parse(&xml, body).unwrap();
         // Assert
         assert_eq!(
-            event.correlation_id(), "a1b2c3d4",
-            "correlation_id was not `a1b2c3d4`, value was `{}`",
+            event.correlation_id(), Some("a1b2c3d4"),
+            "correlation_id was not `a1b2c3d4`, value was `{:?}`",
             event.correlation_id()
         )
     }
@@ -583,12 +1070,274 @@ mod tests {
 </triggerExportResponse>"##;
         let xml = Element::parse(body.as_bytes()).unwrap();
         // Act
-        let event = TriggerExportResponse::new(xml, body);
+        let event = TriggerExportResponse::parse(&xml, body).unwrap();
         // Assert
         assert_eq!(
-            event.correlation_id(), "a1b2c3d4",
-            "correlation_id was not `a1b2c3d4`, value was `{}`",
+            event.correlation_id(), Some("a1b2c3d4"),
+            "correlation_id was not `a1b2c3d4`, value was `{:?}`",
             event.correlation_id()
         )
     }
+    #[test]
+    fn test_event_timestamp_normalized() {
+        // Arrange
+        let body = r##"<triggerExportResponse>
+  <timestamp>2021-02-03T20:21:02,032132132+01:00</timestamp>
+  <correlationId>a1b2c3d4</correlationId>
+</triggerExportResponse>"##;
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        // Act
+        let event = TriggerExportResponse::parse(&xml, body).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&event.to_json().unwrap()).unwrap();
+        // Assert
+        assert_eq!(event.timestamp(), Utc.ymd(2021, 2, 3).and_hms_nano(19, 21, 2, 32_132_132));
+        assert_eq!(json["event_timestamp"], "2021-02-03T19:21:02.032132132Z");
+    }
+    #[test]
+    fn test_event_timestamp_invalid() {
+        let body = r##"<triggerExportResponse>
+  <timestamp>03/02/2021 20:21</timestamp>
+  <correlationId>a1b2c3d4</correlationId>
+</triggerExportResponse>"##;
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        match TriggerExportResponse::parse(&xml, body) {
+            Err(TransformError::InvalidTimestamp { event, value }) => {
+                assert_eq!(event, "triggerExportResponse");
+                assert_eq!(value, "03/02/2021 20:21");
+            },
+            other => panic!("expected InvalidTimestamp, got {:?}", other),
+        }
+    }
+    #[test]
+    fn test_event_types_registry() {
+        let root_tags: Vec<&str> = event_types().iter().map(|t| t.root_tag).collect();
+        assert_eq!(root_tags.len(), 12);
+        assert!(root_tags.contains(&"essenceArchivedEvent"));
+        let event_type = EventType::lookup("essenceLinkedEvent").unwrap();
+        assert_eq!(event_type.origin, Origin::Vrt);
+        assert!(EventType::lookup("unknownEvent").is_none());
+    }
+    #[test]
+    fn test_event_from_xml() {
+        // Arrange
+        let body = r##"<essenceLinkedEvent>
+  <timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <file>AB00112233.mxf</file>
+  <mediaId>AB00112233</mediaId>
+</essenceLinkedEvent>"##;
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        // Act
+        let event = Event::from_xml(&xml, body).unwrap();
+        let event = event.as_vrt_event();
+        // Assert
+        assert_eq!(event.name(), "essenceLinkedEvent");
+        assert_eq!(event.origin(), Origin::Vrt);
+        assert_eq!(event.media_id(), Some("AB00112233"));
+        assert_eq!(event.correlation_id(), None);
+        assert!(event.to_json().unwrap().contains(r#""origin":"vrt""#));
+    }
+    #[test]
+    fn test_event_from_xml_unknown() {
+        let body = "<unknownEvent><foo>bar</foo></unknownEvent>";
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        match Event::from_xml(&xml, body) {
+            Err(TransformError::UnknownRootTag(tag)) => assert_eq!(tag, "unknownEvent"),
+            other => panic!("expected UnknownRootTag, got {:?}", other),
+        }
+    }
+    #[test]
+    fn test_unknown_event_generic_fallback() {
+        let body = "<unknownEvent><foo>bar</foo></unknownEvent>";
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        let transformer = Transformer { unknown_events: UnknownEvents::Generic, ..Transformer::default() };
+        let json = transformer.handle_xml(xml.clone(), body, &Source::default()).unwrap().json;
+        assert!(json.contains(r#""event_known":false"#));
+        assert!(json.contains(r#""foo":"bar""#));
+        assert!(matches!(
+            Transformer::default().handle_xml(xml, body, &Source::default()),
+            Err(TransformError::UnknownRootTag(_))
+        ));
+    }
+    #[test]
+    fn test_document_event_id() {
+        let body = r##"<essenceLinkedEvent>
+  <timestamp>2021-02-03T20:21:02Z</timestamp><file>f</file><mediaId>m</mediaId>
+</essenceLinkedEvent>"##;
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        let transformer = Transformer { event_id: EventIdStrategy::MessageId, ..Transformer::default() };
+        let properties = AmqpProperties::default().with_message_id("msg-1".to_string());
+        let document = transformer.handle_xml(xml, body, &Source::amqp(&properties, "vrt_events_xml")).unwrap();
+        assert_eq!(document.event_id, "msg-1");
+        assert!(document.json.starts_with(r#"{"event_id":"msg-1","event_name":"essenceLinkedEvent","#));
+        let publish = document.publish("vrt.events");
+        assert_eq!(publish.properties.message_id().as_deref(), Some("msg-1"));
+        assert_eq!(publish.body, document.json.as_bytes());
+        assert!(publish.mandatory);
+    }
+    #[test]
+    fn test_namespaced_event() {
+        let body = r##"<ns2:essenceArchivedEvent xmlns:ns2="http://www.vrt.be/mam/viaa/s3" xmlns="http://www.vrt.be/mam/viaa">
+  <ns2:timestamp>2021-02-03T20:21:02.032+01:00</ns2:timestamp>
+  <file>AB00112233.mxf</file>
+  <ns2:pid>AB00112233</ns2:pid>
+  <md5sum>1234abcd</md5sum>
+  <ns2:s3bucket>bucket</ns2:s3bucket>
+</ns2:essenceArchivedEvent>"##;
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        let json = Transformer::default().handle_xml(xml.clone(), body, &Source::default()).unwrap().json;
+        assert!(json.contains(r#""event_namespace":"http://www.vrt.be/mam/viaa/s3""#));
+        assert!(json.contains(r#""pid":"AB00112233""#));
+        assert!(json.contains(r#""s3_bucket":"bucket""#));
+
+        let transformer = Transformer {
+            namespaces: vec!["http://www.vrt.be/mam/viaa".to_string()],
+            ..Transformer::default()
+        };
+        match transformer.handle_xml(xml, body, &Source::default()) {
+            Err(TransformError::UnknownRootTag(tag)) => {
+                assert_eq!(tag, "{http://www.vrt.be/mam/viaa/s3}essenceArchivedEvent")
+            },
+            other => panic!("expected UnknownRootTag, got {:?}", other),
+        }
+        // No namespace is always accepted, and not recorded
+        let body = r##"<essenceLinkedEvent>
+  <timestamp>2021-02-03T20:21:02Z</timestamp><file>f</file><mediaId>m</mediaId>
+</essenceLinkedEvent>"##;
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        let json = transformer.handle_xml(xml, body, &Source::default()).unwrap().json;
+        assert!(!json.contains("event_namespace"));
+    }
+    #[test]
+    fn test_origin_rules() {
+        let body = r##"<essenceLinkedEvent>
+  <timestamp>2021-02-03T20:21:02Z</timestamp><file>f</file><mediaId>m</mediaId>
+</essenceLinkedEvent>"##;
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        let transformer = Transformer {
+            origins: OriginRules::load(Path::new("origins.example.yaml")).unwrap(),
+            ..Transformer::default()
+        };
+        let origin = |queue| {
+            let properties = AmqpProperties::default();
+            let json = transformer.handle_xml(xml.clone(), body, &Source::amqp(&properties, queue)).unwrap().json;
+            serde_json::from_str::<serde_json::Value>(&json).unwrap()["origin"].clone()
+        };
+        assert_eq!(origin("rtbf_events_xml"), "rtbf");
+        // No rule matches: the built-in origin
+        assert_eq!(origin("vrt_events_xml"), "vrt");
+    }
+    #[test]
+    fn test_origin_names() {
+        assert_eq!(Origin::from("meemoo"), Origin::Meemoo);
+        assert_eq!(Origin::from("rtbf"), Origin::Partner("rtbf".to_string()));
+        assert_eq!(Origin::Partner("rtbf".to_string()).to_str(), "rtbf");
+        assert_eq!(serde_json::to_string(&Origin::Meemoo).unwrap(), r#""meemoo""#);
+        assert_eq!(serde_json::to_string(&Origin::Partner("rtbf".to_string())).unwrap(), r#""rtbf""#);
+    }
+    #[test]
+    fn test_missing_required_field() {
+        // Arrange: no <md5sum>
+        let body = r##"<essenceArchivedEvent>
+  <timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <file>AB00112233.mxf</file>
+  <pid>AB00112233</pid>
+  <s3bucket>bucket</s3bucket>
+</essenceArchivedEvent>"##;
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        // Act
+        let result = EssenceArchivedEvent::parse(&xml, body);
+        // Assert
+        match result {
+            Err(TransformError::MissingField { event, field }) => {
+                assert_eq!(event, "essenceArchivedEvent");
+                assert_eq!(field, "md5sum");
+            },
+            other => panic!("expected MissingField, got {:?}", other),
+        }
+    }
+    #[test]
+    fn test_config_queue_defaults() {
+        let config = test_config(vec![]);
+        assert_eq!(config.amqp_in_queue, "vrt2elk_events_xml_q");
+        assert_eq!(config.amqp_out_queue, "vrt2elk_events_json_q");
+        assert_eq!(config.amqp_out_exchange, "");
+        assert_eq!(config.routing_key(), "vrt2elk_events_json_q");
+        assert_eq!(config.amqp_port(), "5672");
+        assert!(!config.amqp_declare);
+    }
+    #[test]
+    fn test_config_amqps_port() {
+        let config = test_config(vec![("AMQP_SCHEME", "amqps"), ("AMQP_PORT", "")]);
+        assert_eq!(config.amqp_port(), "5671");
+        let config = test_config(vec![("AMQP_SCHEME", "amqps"), ("AMQP_PORT", "15671")]);
+        assert_eq!(config.amqp_port(), "15671");
+    }
+    #[test]
+    fn test_config_queues_from_env() {
+        let config = test_config(vec![
+            ("AMQP_IN_QUEUE", "staging_xml_q"),
+            ("AMQP_OUT_EXCHANGE", "vrt_events"),
+            ("AMQP_OUT_EXCHANGE_TYPE", "topic"),
+            ("AMQP_ROUTING_KEY", "vrt.events.json"),
+            ("AMQP_DECLARE", "true"),
+        ]);
+        assert_eq!(config.amqp_in_queue, "staging_xml_q");
+        assert_eq!(config.amqp_out_exchange_type, ExchangeKind::Topic);
+        assert_eq!(config.routing_key(), "vrt.events.json");
+        assert!(config.amqp_declare);
+    }
+    #[test]
+    fn test_get_metadata_response_metadata() {
+        // Arrange
+        let body = r##"<getMetadataResponse>
+  <timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <correlationId>a1b2c3d4</correlationId>
+  <status>SUCCESS</status>
+  <metadata>
+    <title>Het Journaal</title>
+    <programId>P0001</programId>
+    <channel>een</channel>
+  </metadata>
+</getMetadataResponse>"##;
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        // Act
+        let event = GetMetadataResponse::parse(&xml, body).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&event.to_json().unwrap()).unwrap();
+        // Assert
+        assert_eq!(json["metadata"]["titles"][0]["value"], "Het Journaal");
+        assert_eq!(json["metadata"]["program_id"], "P0001");
+        assert_eq!(json["metadata"]["extra"]["channel"], "een");
+    }
+    #[test]
+    fn test_closed_ot_available_event() {
+        // Arrange
+        let body = r##"<closedOtAvailableEvent>
+  <timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <ot>
+    <mediaId>AB00112233</mediaId>
+    <otType>CLOSED</otType>
+    <path>/subtitles/AB00112233.stl</path>
+  </ot>
+</closedOtAvailableEvent>"##;
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        // Act
+        let event = Event::from_xml(&xml, body).unwrap();
+        let event = event.as_vrt_event();
+        let json: serde_json::Value = serde_json::from_str(&event.to_json().unwrap()).unwrap();
+        // Assert
+        assert_eq!(event.media_id(), Some("AB00112233"));
+        assert_eq!(json["ot_type"], "CLOSED");
+        assert_eq!(json["subtitle_path"], "/subtitles/AB00112233.stl");
+        assert!(json.get("subtitle_format").is_none());
+    }
+    #[test]
+    fn test_ot_available_event_without_media_id() {
+        let body = r##"<openOtAvailableEvent>
+  <timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <ot><otType>OPEN</otType></ot>
+</openOtAvailableEvent>"##;
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        let event = Event::from_xml(&xml, body).unwrap();
+        assert_eq!(event.as_vrt_event().media_id(), Some("n/a"));
+    }
 }
@@ -1,200 +1,833 @@
-use std::str;
+use std::cell::Cell;
+use std::env;
+use std::error::Error;
+use std::io;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use amiquip::{Connection, ConsumerMessage, ConsumerOptions, Exchange, Publish, Result};
-use xmltree::{Element, ParseError};
-use envy;
+use chrono::Utc;
+use amiquip::{
+    AmqpValue, Channel, Confirm, Connection, Consumer, ConsumerMessage, ConsumerOptions, Delivery, Exchange,
+    ExchangeDeclareOptions, ExchangeType, FieldTable, Publish, Queue, QueueDeclareOptions, Result, Return,
+};
+use native_tls::TlsConnector;
+use crossbeam_channel::{select, Receiver, RecvTimeoutError};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 
 use amqp2elastic::*;
+use amqp2elastic::confirms::{ConfirmOutcome, PendingConfirms};
+use amqp2elastic::elastic::{BulkSink, ItemStatus};
+use amqp2elastic::envelope::ErrorEnvelope;
+use amqp2elastic::health;
+use amqp2elastic::metrics::{self, Outcome};
+use amqp2elastic::redelivery::{self, Failure, RedeliverySchedule};
+use amqp2elastic::supervisor::{ConnectionState, RetryPolicy, Supervisor};
 
 #[macro_use]
 extern crate log;
 extern crate serde_derive;
 
-// Declare some constants
-const IN_QUEUE: &'static str = "vrt2elk_events_xml_q";
-const OUT_QUEUE: &'static str = "vrt2elk_events_json_q";
-
-
-fn handle_xml(xml: Element, body: &str) -> Result<String, &'static str> {
-    debug!("{:#?}", xml);
-    let root_tag = String::from(&xml.name);
-    info!("Root tag is: {:#?}", root_tag);
-    match root_tag.as_str() {
-        "essenceArchivedEvent" => {
-            let event = EssenceArchivedEvent::new(xml, body);
-            debug!("{:?}", event);
-            // Serialize it to a JSON string
-            let j = event.to_json();
-            Ok(j)
-        },
-        "essenceLinkedEvent" => {
-            let event = EssenceLinkedEvent::new(xml, body);
-            debug!("{:?}", event);
-            // Serialize it to a JSON string
-            let j = event.to_json();
-            Ok(j)
-        },
-        "essenceUnlinkedEvent" => {
-            let event = EssenceUnlinkedEvent::new(xml, body);
-            debug!("{:?}", event);
-            // Serialize it to a JSON string
-            let j = event.to_json();
-            Ok(j)
-        },
-        "objectDeletedEvent" => {
-            let event = ObjectDeletedEvent::new(xml, body);
-            debug!("{:?}", event);
-            // Serialize it to a JSON string
-            let j = event.to_json();
-            Ok(j)
-        },
-        "getMetadataRequest" => {    
-            let event = GetMetadataRequest::new(xml, body);
-            debug!("{:?}", event);
-            // Serialize it to a JSON string
-            let j = event.to_json();
-            Ok(j)
-        },
-        "getMetadataResponse" => {
-            let event = GetMetadataResponse::new(xml, body);
-            debug!("{:?}", event);
-            // Serialize it to a JSON string
-            let j = event.to_json();
-            Ok(j)
-        },
-        "metadataUpdatedEvent" => {
-            let event = MetadataUpdatedEvent::new(xml, body);
-            debug!("{:?}", event);
-            // Serialize it to a JSON string
-            let j = event.to_json();
-            Ok(j)
-        },
-        "closedOtAvailableEvent" => {
-            let event = ClosedOtAvailableEvent::new(xml, body);
-            debug!("{:?}", event);
-            // Serialize it to a JSON string
-            let j = event.to_json();
-            Ok(j)
-        },
-        "openOtAvailableEvent" => {
-            let event = OpenOtAvailableEvent::new(xml, body);
-            debug!("{:?}", event);
-            // Serialize it to a JSON string
-            let j = event.to_json();
-            Ok(j)
-        },
-        "makeSubtitleAvailableRequest" => {
-            let event = MakeSubtitleAvailableRequest::new(xml, body);
-            debug!("{:?}", event);
-            // Serialize it to a JSON string
-            let j = event.to_json();
-            Ok(j)
-        },
-        "triggerExportRequest" => {
-            let event = TriggerExportRequest::new(xml, body);
-            debug!("{:?}", event);
-            // Serialize it to a JSON string
-            let j = event.to_json();
-            Ok(j)
-        },
-        "triggerExportResponse" => {
-            let event = TriggerExportResponse::new(xml, body);
-            debug!("{:?}", event);
-            // Serialize it to a JSON string
-            let j = event.to_json();
-            Ok(j)
-        },
-        _ => {
-            warn!("Unknown event type: {:#?}", root_tag);
-            Err("Unknown event type")
-        },
+const USAGE: &str = "Usage: amqp2elastic [COMMAND]
+
+Without a command, consume XML messages from the input queue and publish (or
+index) them as JSON. Configured through environment variables, see
+.env.example.
+
+Commands:
+    convert     Convert XML files to JSON, without a broker
+    replay      Transform the messages in the dead-letter queue again
+    mapping     Print the Elasticsearch index template for the documents
+    schema      Print the JSON Schemas of the documents";
+
+/// How long to wait for a delivery when there is nothing else to do.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A transformed delivery, waiting for the output to accept its JSON; or a
+/// document derived by the correlation tracker, with no delivery to settle.
+struct InFlight {
+    delivery: Option<Delivery>,
+    root_tag: String,
+    event_id: String,
+    since: Instant,
+}
+
+impl InFlight {
+    fn derived(event_name: String, document: &Document) -> InFlight {
+        InFlight { delivery: None, root_tag: event_name, event_id: document.event_id.clone(), since: Instant::now() }
+    }
+}
+
+/// Tell the tracker whether the output took a derived document, or it has to
+/// be sent again.
+fn settle_derived(tracker: &mut Option<Tracker>, in_flight: &InFlight, accepted: bool) {
+    if let Some(tracker) = tracker.as_mut() {
+        if accepted {
+            tracker.accepted(&in_flight.event_id);
+        } else {
+            tracker.failed(&in_flight.event_id);
+        }
     }
 }
 
-fn handle_error(err: ParseError) {
-    warn!("Error: {}", err);
-    warn!("If a DLX was specified for q:{}, find the message there", IN_QUEUE);
+/// The publisher confirms of the output channel, and the messages the broker
+/// returned because no queue took them. The broker sends the return of a
+/// message before its confirm, so it has arrived when the confirm is settled.
+struct Confirms {
+    receiver: Receiver<Confirm>,
+    returns: Receiver<Return>,
+    /// Message ids of the returned messages not settled yet.
+    returned: Vec<String>,
 }
 
-fn format_connection_string(config: &Config) -> String {
-    format!("amqp://{}:{}@{}:{}/{}",
-        config.amqp_user,
-        config.amqp_passwd,
-        config.amqp_host,
-        config.amqp_port,
-        config.amqp_vhost)
+impl Confirms {
+    fn never() -> Confirms {
+        Confirms { receiver: crossbeam_channel::never(), returns: crossbeam_channel::never(), returned: Vec::new() }
+    }
+
+    /// Mark the acked messages that were returned as such.
+    fn check_returns(&mut self, outcomes: Vec<(InFlight, ConfirmOutcome)>) -> Vec<(InFlight, ConfirmOutcome)> {
+        self.returned.extend(self.returns.try_iter().filter_map(|returned| returned.properties.message_id().clone()));
+        outcomes.into_iter()
+            .map(|(in_flight, outcome)| {
+                // Also forget the return of a message that timed out
+                match self.returned.iter().position(|message_id| *message_id == in_flight.event_id) {
+                    Some(i) => {
+                        self.returned.swap_remove(i);
+                        let outcome = if outcome == ConfirmOutcome::Acked { ConfirmOutcome::Returned } else { outcome };
+                        (in_flight, outcome)
+                    },
+                    None => (in_flight, outcome),
+                }
+            })
+            .collect()
+    }
 }
 
-fn main() -> Result<()> {
-    // First and foremost, initialize the logger
-    env_logger::init();
+/// Save the correlation state: only every `SAVE_INTERVAL` while consuming
+/// (`throttled`), then once more when done.
+fn save_state(tracker: &mut Option<Tracker>, throttled: bool) {
+    if let Some(tracker) = tracker.as_mut() {
+        let saved = if throttled { tracker.save_if_due() } else { tracker.save() };
+        if let Err(e) = saved {
+            warn!("Cannot save the correlation state: {}", e);
+        }
+    }
+}
 
-    // Get our configuration from the environment
-    // The necessary environment variables can be found in the `.env` file
-    let config = match envy::from_env::<Config>() {
-       Ok(config) => config,
-       Err(error) => panic!("{:#?}", error)
+/// Settles the deliveries that could not be handled. Permanent failures are
+/// rejected to the DLX, after publishing an error envelope for each to
+/// `AMQP_ERROR_QUEUE`, if set. Transient ones are redelivered after a delay
+/// (see the `redelivery` module), or requeued right away if that is disabled.
+struct Failures<'a> {
+    in_queue: &'a str,
+    /// A separate channel for the envelopes, so they don't count towards
+    /// the publisher confirms of the output channel.
+    error_queue: Option<(Channel, &'a str)>,
+    redelivery: Option<Redelivery>,
+}
+
+impl Failures<'_> {
+    fn handle(&self, consumer: &Consumer, delivery: Delivery, root_tag: &str, failure: Failure) -> Result<()> {
+        if !failure.is_transient() {
+            return self.reject(consumer, delivery, root_tag, &failure);
+        }
+        let redelivery = match &self.redelivery {
+            Some(redelivery) => redelivery,
+            None => {
+                warn!("{}, requeueing", failure.message());
+                return consumer.reject(delivery, true);
+            },
+        };
+        let attempt = redelivery::retry_count(&delivery.properties) + 1;
+        let delay = match redelivery.schedule.delay(attempt) {
+            Some(delay) => delay,
+            None => {
+                metrics::message(root_tag, Outcome::Rejected);
+                let failure = Failure::Permanent {
+                    kind: failure.kind(),
+                    message: format!("{} (gave up after {} retries)", failure.message(), attempt - 1),
+                };
+                return self.reject(consumer, delivery, root_tag, &failure);
+            },
+        };
+        if redelivery.publish(&delivery, self.in_queue, attempt, delay) {
+            info!("{}, retrying in {:?} (attempt {})", failure.message(), delay, attempt);
+            metrics::message(root_tag, Outcome::Retried);
+            consumer.ack(delivery)
+        } else {
+            warn!("{}, and the retry queue didn't accept it: requeueing", failure.message());
+            consumer.reject(delivery, true)
+        }
+    }
+
+    fn reject(&self, consumer: &Consumer, delivery: Delivery, root_tag: &str, failure: &Failure) -> Result<()> {
+        warn!("Error: {}", failure.message());
+        if let Some((channel, error_queue)) = &self.error_queue {
+            // Best effort: the message itself still goes to the DLX.
+            let published = ErrorEnvelope::for_delivery(&delivery, failure.kind(), failure.message())
+                .root_tag(root_tag)
+                .to_json()
+                .map_err(Box::<dyn Error>::from)
+                .and_then(|json| {
+                    channel.basic_publish("", Publish::new(json.as_bytes(), *error_queue)).map_err(Box::from)
+                });
+            if let Err(e) = published {
+                warn!("Failed to publish error envelope to q:{}: {}", error_queue, e);
+            }
+        }
+        warn!("If a DLX was specified for q:{}, find the message there (see `amqp2elastic replay`)", self.in_queue);
+        consumer.reject(delivery, false)
+    }
+}
+
+/// A channel with publisher confirms, to republish deliveries to the retry
+/// queues.
+struct Redelivery {
+    channel: Channel,
+    confirms: Receiver<Confirm>,
+    schedule: RedeliverySchedule,
+    confirm_timeout: Duration,
+    /// Delivery tag of the last publish on `channel`.
+    published: Cell<u64>,
+}
+
+impl Redelivery {
+    /// Open the channel, and declare the retry queues on it (with
+    /// `AMQP_DECLARE`).
+    fn open(connection: &mut Connection, config: &Config, schedule: RedeliverySchedule) -> Result<Redelivery> {
+        let channel = connection.open_channel(None)?;
+        for (queue, delay) in schedule.queues(&config.amqp_in_queue) {
+            if config.amqp_declare {
+                channel.queue_declare(queue.as_str(), QueueDeclareOptions {
+                    durable: true,
+                    arguments: redelivery::queue_arguments(&config.amqp_in_queue, delay),
+                    ..QueueDeclareOptions::default()
+                })?;
+            } else {
+                channel.queue_declare_passive(queue.as_str())?;
+            }
+            info!("Redelivering after {:?} via q:{}", delay, queue);
+        }
+        let confirms = channel.listen_for_publisher_confirms()?;
+        channel.enable_publisher_confirms()?;
+        Ok(Redelivery {
+            channel,
+            confirms,
+            schedule,
+            confirm_timeout: Duration::from_millis(config.amqp_confirm_timeout_ms),
+            published: Cell::new(0),
+        })
+    }
+
+    /// Publish the delivery to the retry queue for `delay`, as retry number
+    /// `attempt`, and wait for the broker to confirm it.
+    fn publish(&self, delivery: &Delivery, in_queue: &str, attempt: u32, delay: Duration) -> bool {
+        let queue = redelivery::queue_name(in_queue, delay);
+        let properties = redelivery::with_retry_count(&delivery.properties, attempt);
+        let publish = Publish::with_properties(&delivery.body, queue.as_str(), properties);
+        if let Err(e) = self.channel.basic_publish("", publish) {
+            warn!("Failed to publish to q:{}: {}", queue, e);
+            return false;
+        }
+        let tag = self.published.get() + 1;
+        self.published.set(tag);
+        let deadline = Instant::now() + self.confirm_timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.confirms.recv_timeout(remaining) {
+                // Skip late confirms of earlier publishes that timed out
+                Ok(Confirm::Ack(payload)) | Ok(Confirm::Nack(payload)) if payload.delivery_tag < tag => continue,
+                Ok(Confirm::Ack(_)) => return true,
+                Ok(Confirm::Nack(_)) | Err(_) => return false,
+            }
+        }
+    }
+}
+
+/// Send the batched documents to Elasticsearch and ack or reject the
+/// corresponding deliveries according to their indexing status.
+fn flush_to_elastic(
+    sink: &mut BulkSink<InFlight>,
+    consumer: &Consumer,
+    failures: &Failures,
+    tracker: &mut Option<Tracker>,
+) -> Result<()> {
+    if sink.is_empty() {
+        return Ok(());
+    }
+    debug!("Flushing {} documents to Elasticsearch", sink.len());
+    let started = Instant::now();
+    let statuses = sink.flush();
+    metrics::observe_publish("elasticsearch", started.elapsed());
+    // Not ready while every document has to be retried (eg. ES is down).
+    health::set_output_ready(statuses.iter().any(|(_, status)| !matches!(status, ItemStatus::Retry(_))));
+    for (in_flight, status) in statuses {
+        let delivery = match in_flight.delivery {
+            Some(delivery) => delivery,
+            None => {
+                match &status {
+                    ItemStatus::Indexed => settle_derived(tracker, &in_flight, true),
+                    ItemStatus::Retry(_) => settle_derived(tracker, &in_flight, false),
+                    ItemStatus::Rejected(reason) => {
+                        warn!("Dropping derived {} document: {}", in_flight.root_tag, reason);
+                        settle_derived(tracker, &in_flight, true);
+                    },
+                }
+                continue;
+            },
+        };
+        match status {
+            ItemStatus::Indexed => {
+                metrics::message(&in_flight.root_tag, Outcome::Processed);
+                consumer.ack(delivery)?;
+            },
+            ItemStatus::Retry(reason) => {
+                let failure = Failure::Transient {
+                    kind: "elasticsearch_unavailable",
+                    message: format!("Indexing failed: {}", reason),
+                };
+                failures.handle(consumer, delivery, &in_flight.root_tag, failure)?;
+            },
+            ItemStatus::Rejected(reason) => {
+                metrics::message(&in_flight.root_tag, Outcome::Rejected);
+                let failure = Failure::Permanent {
+                    kind: "elasticsearch_rejected",
+                    message: format!("Indexing rejected: {}", reason),
+                };
+                failures.handle(consumer, delivery, &in_flight.root_tag, failure)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Ack the deliveries whose published JSON was confirmed by the broker;
+/// retry or dead-letter those that were nacked, returned or never confirmed.
+fn settle_confirms(
+    outcomes: Vec<(InFlight, ConfirmOutcome)>,
+    consumer: &Consumer,
+    config: &Config,
+    failures: &Failures,
+    tracker: &mut Option<Tracker>,
+) -> Result<()> {
+    for (in_flight, outcome) in outcomes {
+        let delivery = match in_flight.delivery {
+            Some(delivery) => delivery,
+            None => {
+                settle_derived(tracker, &in_flight, outcome == ConfirmOutcome::Acked);
+                continue;
+            },
+        };
+        match outcome {
+            ConfirmOutcome::Acked => {
+                health::set_output_ready(true);
+                metrics::observe_publish("amqp", in_flight.since.elapsed());
+                metrics::message(&in_flight.root_tag, Outcome::Processed);
+                consumer.ack(delivery)?;
+            },
+            ConfirmOutcome::Nacked | ConfirmOutcome::TimedOut | ConfirmOutcome::Returned => {
+                health::set_output_ready(false);
+                let (kind, message) = match outcome {
+                    ConfirmOutcome::Nacked => ("publish_nacked", "Publish was nacked by the broker"),
+                    ConfirmOutcome::Returned => ("publish_returned", "Publish was returned: no queue took it"),
+                    _ => ("publish_timed_out", "Publish was not confirmed in time"),
+                };
+                let message = format!("{} (delivery {})", message, delivery.delivery_tag());
+                let failure = if config.amqp_confirm_requeue {
+                    Failure::Transient { kind, message }
+                } else {
+                    metrics::message(&in_flight.root_tag, Outcome::Rejected);
+                    Failure::Permanent { kind, message }
+                };
+                failures.handle(consumer, delivery, &in_flight.root_tag, failure)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Wait for the confirms of all messages published so far (or for them to
+/// time out), but no longer than `deadline`.
+fn drain_confirms(
+    pending: &mut PendingConfirms<InFlight>,
+    confirms: &mut Confirms,
+    consumer: &Consumer,
+    config: &Config,
+    failures: &Failures,
+    tracker: &mut Option<Tracker>,
+    deadline: Instant,
+) -> Result<()> {
+    while let Some(timeout) = pending.time_until_expiry() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            warn!("Shutdown deadline reached with {} unconfirmed messages, they will be redelivered", pending.len());
+            break;
+        }
+        let outcomes = match confirms.receiver.recv_timeout(timeout.min(remaining)) {
+            Ok(confirm) => pending.confirm(confirm),
+            Err(RecvTimeoutError::Timeout) => pending.expire(),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        settle_confirms(confirms.check_returns(outcomes), consumer, config, failures, tracker)?;
+    }
+    Ok(())
+}
+
+fn exchange_type(kind: ExchangeKind) -> ExchangeType {
+    match kind {
+        ExchangeKind::Direct => ExchangeType::Direct,
+        ExchangeKind::Topic => ExchangeType::Topic,
+        ExchangeKind::Fanout => ExchangeType::Fanout,
+        ExchangeKind::Headers => ExchangeType::Headers,
+    }
+}
+
+/// Get handles to the input queue and the output exchange. With
+/// `AMQP_DECLARE`, the queues, exchange and binding are declared (durable);
+/// otherwise they should already be declared/configured on the broker.
+///
+/// An input queue that already exists is used as is, as redeclaring it with
+/// other arguments fails; a new one dead-letters to `AMQP_DLQ`, if set.
+fn declare_topology<'a>(
+    connection: &mut Connection,
+    channel: &'a Channel,
+    config: &Config,
+) -> Result<(Queue<'a>, Exchange<'a>)> {
+    if !config.amqp_declare {
+        let in_queue = channel.queue_declare_passive(config.amqp_in_queue.as_str())?;
+        return Ok((in_queue, connection::output_exchange(channel, config)?));
+    }
+    let durable = QueueDeclareOptions { durable: true, ..QueueDeclareOptions::default() };
+    let in_queue = if queue_exists(connection, &config.amqp_in_queue)? {
+        info!("q:{} already exists, keeping its arguments", config.amqp_in_queue);
+        channel.queue_declare_passive(config.amqp_in_queue.as_str())?
+    } else {
+        let mut arguments = FieldTable::new();
+        match setting(&config.amqp_dlq) {
+            Some(dlq) => {
+                channel.queue_declare(dlq, durable.clone())?;
+                arguments.insert("x-dead-letter-exchange".to_string(), AmqpValue::LongString(String::new()));
+                arguments.insert("x-dead-letter-routing-key".to_string(), AmqpValue::LongString(dlq.to_string()));
+                info!("Declared q:{} dead-lettering to q:{}", config.amqp_in_queue, dlq);
+            },
+            None => warn!("No AMQP_DLQ: messages rejected from q:{} are discarded", config.amqp_in_queue),
+        }
+        channel.queue_declare(config.amqp_in_queue.as_str(), QueueDeclareOptions { arguments, ..durable.clone() })?
+    };
+    let out_queue = channel.queue_declare(config.amqp_out_queue.as_str(), durable)?;
+    if config.amqp_out_exchange.is_empty() {
+        return Ok((in_queue, Exchange::direct(channel)));
+    }
+    let exchange = channel.exchange_declare(
+        exchange_type(config.amqp_out_exchange_type),
+        config.amqp_out_exchange.as_str(),
+        ExchangeDeclareOptions { durable: true, ..ExchangeDeclareOptions::default() },
+    )?;
+    out_queue.bind(&exchange, config.routing_key(), FieldTable::new())?;
+    info!("Declared q:{} bound to x:{} with routing key {}",
+        config.amqp_out_queue, config.amqp_out_exchange, config.routing_key());
+    Ok((in_queue, exchange))
+}
+
+/// Whether `queue` exists, checked on a channel of its own: the broker
+/// closes the channel if it doesn't.
+fn queue_exists(connection: &mut Connection, queue: &str) -> Result<bool> {
+    let channel = connection.open_channel(None)?;
+    let exists = match channel.queue_declare_passive(queue) {
+        Ok(_) => true,
+        Err(amiquip::Error::ServerClosedChannel { code: 404, .. }) => false,
+        Err(err) => return Err(err),
     };
+    if exists {
+        channel.close()?;
+    }
+    Ok(exists)
+}
+
+/// Open the channel for error envelopes, and declare the error queue on it
+/// (durable, with `AMQP_DECLARE`).
+fn declare_error_queue(connection: &mut Connection, config: &Config, error_queue: &str) -> Result<Channel> {
+    let channel = connection.open_channel(None)?;
+    if config.amqp_declare {
+        channel.queue_declare(error_queue, QueueDeclareOptions { durable: true, ..QueueDeclareOptions::default() })?;
+    } else {
+        channel.queue_declare_passive(error_queue)?;
+    }
+    info!("Publishing error envelopes to q:{}", error_queue);
+    Ok(channel)
+}
+
+/// How a consuming session ended.
+enum SessionEnd {
+    /// The consumer was cancelled or closed from our side.
+    Shutdown,
+    /// The connection or channel was closed by the broker or the network.
+    Lost(String),
+}
 
+/// Forward SIGTERM and SIGINT to a channel.
+fn shutdown_signals() -> io::Result<Receiver<i32>> {
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    thread::Builder::new()
+        .name(String::from("signals"))
+        .spawn(move || {
+            for signal in signals.forever() {
+                if sender.send(signal).is_err() {
+                    break;
+                }
+            }
+        })?;
+    Ok(receiver)
+}
+
+/// Connect, consume from the in_queue and handle deliveries until the
+/// consumer ends. On a shutdown signal, the consumer is cancelled and the
+/// deliveries in flight are finished within `SHUTDOWN_TIMEOUT_MS`.
+fn consume(
+    config: &Config,
+    tls: Option<&TlsConnector>,
+    transformer: &Transformer,
+    tracker: &mut Option<Tracker>,
+    supervisor: &mut Supervisor,
+    shutdown: &Receiver<i32>,
+) -> Result<SessionEnd, Box<dyn Error>> {
     // Open connection.
-    let connection_string = format_connection_string(&config);
-    let mut connection = Connection::insecure_open(&connection_string)?;
+    // Derived documents handed to the output in an earlier session are sent again
+    if let Some(tracker) = tracker.as_mut() {
+        tracker.retry_in_flight();
+    }
+    supervisor.transition(ConnectionState::Connecting);
+    let mut connection = connection::open_connection(config, tls)?;
+    supervisor.transition(ConnectionState::Connected);
 
     // Open a channel - None says let the library choose the channel ID.
     let channel = connection.open_channel(None)?;
     // Set the prefetch count limit.
     let prefetch_count = config.amqp_prefetch_count;
     let _qos_result = channel.qos(0, prefetch_count, false);
+    metrics::set_prefetch_count(prefetch_count);
 
-    // Passively declare the in_queue (meaning the queue should already be
-    // declared/configured on the broker).
-    let in_queue = channel.queue_declare_passive(IN_QUEUE)?;
+    // Get the in_queue, and the exchange to publish to.
+    let (in_queue, exchange) = declare_topology(&mut connection, &channel, config)?;
+    let in_queue_name = config.amqp_in_queue.as_str();
+    let schedule = RedeliverySchedule::from_config(config);
+    let failures = Failures {
+        in_queue: in_queue_name,
+        error_queue: match setting(&config.amqp_error_queue) {
+            Some(error_queue) => Some((declare_error_queue(&mut connection, config, error_queue)?, error_queue)),
+            None => None,
+        },
+        redelivery: if schedule.is_enabled() {
+            Some(Redelivery::open(&mut connection, config, schedule)?)
+        } else {
+            None
+        },
+    };
+    // Or index directly into Elasticsearch.
+    let mut elastic = match config.output {
+        Output::Elasticsearch => {
+            if config.es_bulk_size > usize::from(prefetch_count) {
+                warn!("ES_BULK_SIZE ({}) exceeds AMQP_PREFETCH_COUNT ({}): batches will only be flushed on time",
+                    config.es_bulk_size, prefetch_count);
+            }
+            info!("Indexing into Elasticsearch at {}", config.es_url);
+            Some(BulkSink::from_config(config)?)
+        },
+        Output::Amqp => None,
+    };
 
-    // Publish: Get a handle to the direct exchange on our channel.
-    let exchange = Exchange::direct(&channel);
+    // When publishing, use publisher confirms: a delivery is only acked
+    // once its JSON is safely on the broker, in a queue.
+    let (mut confirms, mut pending) = match elastic {
+        None => {
+            let returns = channel.listen_for_returns()?;
+            let receiver = channel.listen_for_publisher_confirms()?;
+            channel.enable_publisher_confirms()?;
+            let timeout = Duration::from_millis(config.amqp_confirm_timeout_ms);
+            (Confirms { receiver, returns, returned: Vec::new() }, Some(PendingConfirms::new(timeout)))
+        },
+        Some(_) => (Confirms::never(), None),
+    };
+    health::set_output_ready(true);
 
     // Start a consumer.
     let consumer = in_queue.consume(ConsumerOptions::default())?;
-    info!("Waiting for messages on q:{}/{} on {}.", config.amqp_vhost, IN_QUEUE, config.amqp_host);
+    supervisor.transition(ConnectionState::Consuming);
+    info!("Waiting for messages on q:{}/{} on {}.", config.amqp_vhost, in_queue_name, config.amqp_host);
     info!("Consumer tag is: {}", consumer.consumer_tag());
     info!("Press Ctrl-C to exit.");
 
-    for (i, message) in consumer.receiver().iter().enumerate() {
+    let shutdown_timeout = Duration::from_millis(config.shutdown_timeout_ms);
+    let mut shutdown_deadline: Option<Instant> = None;
+    let mut i = 0;
+    let end = loop {
+        health::heartbeat();
+        metrics::set_in_flight(
+            elastic.as_ref().map_or(0, BulkSink::len) + pending.as_ref().map_or(0, PendingConfirms::len)
+        );
+        // Wake up in time to flush a pending batch or to expire confirms.
+        let timeout = match (&elastic, &pending) {
+            (Some(sink), _) => Some(sink.time_until_due()),
+            (None, Some(pending)) => pending.time_until_expiry(),
+            (None, None) => None,
+        };
+        let timeout = match shutdown_deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining == Duration::from_secs(0) {
+                    warn!("Shutdown deadline reached, unacked deliveries will be redelivered");
+                    break SessionEnd::Shutdown;
+                }
+                Some(timeout.map_or(remaining, |timeout| timeout.min(remaining)))
+            },
+            None => timeout,
+        };
+        let message = select! {
+            recv(consumer.receiver()) -> message => message.map_err(|_| RecvTimeoutError::Disconnected),
+            recv(confirms.receiver) -> confirm => match (confirm, pending.as_mut()) {
+                (Ok(confirm), Some(pending)) => {
+                    let outcomes = confirms.check_returns(pending.confirm(confirm));
+                    settle_confirms(outcomes, &consumer, config, &failures, tracker)?;
+                    continue;
+                },
+                _ => break SessionEnd::Lost(String::from("Publisher confirms channel closed")),
+            },
+            recv(shutdown) -> _ => {
+                if shutdown_deadline.is_some() {
+                    warn!("Second shutdown signal, not waiting for deliveries in flight");
+                    break SessionEnd::Shutdown;
+                }
+                info!("Shutting down: cancelling the consumer, finishing deliveries in flight");
+                health::set_output_ready(false);
+                consumer.cancel()?;
+                shutdown_deadline = Some(Instant::now() + shutdown_timeout);
+                continue;
+            },
+            default(timeout.unwrap_or(IDLE_TIMEOUT)) => Err(RecvTimeoutError::Timeout),
+        };
+        // The documents to send, with what to settle once they are accepted.
+        let mut outgoing = Vec::new();
         match message {
-            ConsumerMessage::Delivery(delivery) => {
+            Ok(ConsumerMessage::Delivery(delivery)) => {
                 info!("Routing key: {:?}", &delivery.routing_key);
-                let body = str::from_utf8(&delivery.body).unwrap();
-                debug!("({:>3}) Received [{}]", i, body);
-                let xml_result = Element::parse(body.as_bytes());
-                match xml_result {
-                    Ok(xml_tree) => {
-                        let json_event = handle_xml(xml_tree, body);
-                        match json_event {
-                            Ok(json_event) => {
-                                debug!("{:?}", json_event);
-                                exchange.publish(Publish::new(json_event.as_bytes(), OUT_QUEUE))?;
-                                consumer.ack(delivery)?;
-                            },
-                            Err(e) => {
-                                warn!("{}", e);
-                                warn!("If a DLX was specified for q:{}, find the message there", IN_QUEUE);
-                                consumer.reject(delivery, false)?;
-                            }
+                let mut root_tag = String::new();
+                let document = parse_message(&delivery.body)
+                    .and_then(|(xml_tree, body)| {
+                        debug!("({:>3}) Received [{}]", i, body);
+                        root_tag = xml_tree.name.clone();
+                        transformer.handle_xml(xml_tree, body, &Source::amqp(&delivery.properties, in_queue_name))
+                    });
+                i += 1;
+                match document {
+                    Ok(document) => {
+                        debug!("{:?}", document);
+                        // A `correlation_completed` document is sent with the
+                        // derived documents below
+                        if let (Some(tracker), Some(correlated)) = (tracker.as_mut(), &document.correlation) {
+                            tracker.observe(correlated);
                         }
+                        let in_flight = InFlight {
+                            delivery: Some(delivery),
+                            root_tag,
+                            event_id: document.event_id.clone(),
+                            since: Instant::now(),
+                        };
+                        outgoing.push((in_flight, document));
                     },
                     Err(e) => {
-                        handle_error(e);
-                        consumer.reject(delivery, false)?;
+                        metrics::message(&root_tag, Outcome::of(&e));
+                        failures.handle(&consumer, delivery, &root_tag, Failure::from(&e))?;
                     },
                 }
-            }
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(sink) = elastic.as_mut() {
+                    flush_to_elastic(sink, &consumer, &failures, tracker)?;
+                }
+                if let Some(pending) = pending.as_mut() {
+                    settle_confirms(confirms.check_returns(pending.expire()), &consumer, config, &failures, tracker)?;
+                }
+            },
+            Ok(ConsumerMessage::ClientCancelled)
+            | Ok(ConsumerMessage::ClientClosedChannel)
+            | Ok(ConsumerMessage::ClientClosedConnection) => {
+                info!("Consumer ended");
+                break SessionEnd::Shutdown;
+            },
             other => {
                 info!("Consumer ended: {:?}", other);
-                break;
+                break SessionEnd::Lost(format!("{:?}", other));
+            }
+        }
+        // After the message was handled, so breaking out of the loop can't
+        // lose them; they stay in the tracker until the output accepts them.
+        if let Some(tracker) = tracker.as_mut() {
+            tracker.expire(Utc::now());
+            for (event_name, document) in tracker.unsent() {
+                outgoing.push((InFlight::derived(event_name, &document), document));
+            }
+        }
+        for (in_flight, document) in outgoing {
+            match elastic.as_mut() {
+                Some(sink) => sink.push(in_flight, document),
+                None => {
+                    exchange.publish(document.publish(config.routing_key()))?;
+                    match pending.as_mut() {
+                        Some(pending) => pending.published(in_flight),
+                        None => match in_flight.delivery {
+                            Some(delivery) => {
+                                metrics::message(&in_flight.root_tag, Outcome::Processed);
+                                consumer.ack(delivery)?;
+                            },
+                            None => settle_derived(tracker, &in_flight, true),
+                        },
+                    }
+                },
+            }
+        }
+        if let Some(sink) = elastic.as_mut() {
+            if sink.is_due() {
+                flush_to_elastic(sink, &consumer, &failures, tracker)?;
+            }
+        }
+        save_state(tracker, true);
+    };
+    match end {
+        SessionEnd::Shutdown => {
+            if let Some(sink) = elastic.as_mut() {
+                flush_to_elastic(sink, &consumer, &failures, tracker)?;
             }
+            if let Some(pending) = pending.as_mut() {
+                let deadline = shutdown_deadline.unwrap_or_else(|| Instant::now() + shutdown_timeout);
+                drain_confirms(pending, &mut confirms, &consumer, config, &failures, tracker, deadline)?;
+            }
+            connection.close()?;
+            supervisor.transition(ConnectionState::Closed);
+        },
+        SessionEnd::Lost(_) => {
+            // Unacked deliveries (including a pending batch and those
+            // awaiting a confirm) will be redelivered by the broker.
+            let _ = connection.close();
+        },
+    }
+    save_state(tracker, false);
+    Ok(end)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // First and foremost, initialize the logger
+    env_logger::init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = args.first().map(String::as_str);
+    match command {
+        Some("-h") | Some("--help") | Some("help") => {
+            println!("{}", USAGE);
+            return Ok(());
+        },
+        Some("convert") | Some("replay") | Some("mapping") | Some("schema") | None => {},
+        Some(other) => {
+            eprintln!("Unknown command {}\n\n{}", other, USAGE);
+            process::exit(2);
+        },
+    }
+
+    // Get our configuration from the environment
+    // The necessary environment variables can be found in the `.env` file
+    let config = match envy::from_env::<Config>() {
+       Ok(config) => config,
+       Err(error) => panic!("{:#?}", error)
+    };
+    let transformer = match Transformer::from_config(&config) {
+        Ok(transformer) => transformer,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        },
+    };
+    if command == Some("convert") {
+        process::exit(convert::main(&args[1..], &transformer));
+    }
+    if command == Some("mapping") {
+        process::exit(template::main(&args[1..], &config, &transformer));
+    }
+    if command == Some("schema") {
+        process::exit(schema::main(&args[1..], &transformer));
+    }
+
+    // Set up TLS once, so certificate problems are reported right away.
+    let tls = match config.amqp_scheme {
+        AmqpScheme::Amqps => match tls::tls_connector(&config) {
+            Ok(tls) => Some(tls),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            },
+        },
+        AmqpScheme::Amqp => None,
+    };
+
+    if config.output == Output::Elasticsearch {
+        if let Err(e) = elastic::check_index_pattern(&config.es_index_pattern) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+
+    if command == Some("replay") {
+        process::exit(replay::main(&args[1..], &config, tls.as_ref(), &transformer));
+    }
+
+    // Serve the metrics
+    metrics::init();
+    metrics::register_root_tags(transformer.mapping.root_tags());
+    if !config.http_listen_addr.is_empty() {
+        if let Err(e) = server::start(&config.http_listen_addr) {
+            error!("Cannot listen on {}: {}", config.http_listen_addr, e);
+            process::exit(1);
+        }
+    }
+
+    let shutdown = shutdown_signals()?;
+
+    // Pending requests outlive reconnections (and restarts, with a state file).
+    let mut tracker = if config.correlation_tracking {
+        match Tracker::from_config(&config) {
+            Ok(tracker) => Some(tracker),
+            Err(e) => {
+                error!("Cannot set up correlation tracking: {}", e);
+                process::exit(1);
+            },
+        }
+    } else {
+        None
+    };
+
+    // (Re)connect until the consumer is shut down, or we run out of attempts.
+    let mut supervisor = Supervisor::new(RetryPolicy::from_config(&config));
+    loop {
+        let session = consume(&config, tls.as_ref(), &transformer, &mut tracker, &mut supervisor, &shutdown);
+        let (reason, error) = match session {
+            Ok(SessionEnd::Shutdown) => return Ok(()),
+            Ok(SessionEnd::Lost(reason)) => (reason, None),
+            Err(e) => (e.to_string(), Some(e)),
+        };
+        match supervisor.on_failure(&reason) {
+            Some(delay) => {
+                if shutdown.recv_timeout(delay).is_ok() {
+                    info!("Shutting down while disconnected");
+                    return Ok(());
+                }
+            },
+            None => match error {
+                Some(e) => return Err(e),
+                None => process::exit(1),
+            },
         }
     }
-    connection.close()
 }
 
 // TODO: UNIT TESTS
@@ -0,0 +1,410 @@
+//! Event types declared in a mapping file (`MAPPING_FILE`, YAML or TOML)
+//! instead of in code: per root tag, the JSON fields with the element path
+//! to take them from, their type, and whether they are required or have a
+//! default. See `mapping.example.yaml`.
+//!
+//! Element paths are child element names separated by `/`, with `*` for any
+//! element, and may end in `@attribute`; `.` is the root element itself.
+//! Names are matched on the local name, so `ns2:file` and `file` both match
+//! a `<ns2:file>` or `<file>` child.
+//! Where a path matches several elements, the first one is used, unless the
+//! field is `repeated`.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use xmltree::{Element, XMLNode};
+
+use crate::config_file::{self, ConfigFileError};
+use crate::generic::RESERVED;
+use crate::{timestamp, Origin, OriginRules, TransformError, VrtEvent};
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Mapping {
+    /// Event types by root tag, see `Mapping::get`.
+    #[serde(default)]
+    pub events: BTreeMap<String, EventMapping>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EventMapping {
+    #[serde(default = "default_origin")]
+    pub origin: Origin,
+    /// Path of the event timestamp, which is required.
+    #[serde(default = "default_timestamp_path")]
+    pub timestamp: String,
+    /// JSON fields by name.
+    #[serde(default)]
+    pub fields: BTreeMap<String, FieldMapping>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FieldMapping {
+    pub path: String,
+    #[serde(rename = "type", default)]
+    pub kind: FieldType,
+    /// Reject the message if the path matches nothing (or only empty
+    /// elements).
+    #[serde(default)]
+    pub required: bool,
+    /// The value if the path matches nothing; otherwise the field is left
+    /// out.
+    #[serde(default)]
+    pub default: Option<Value>,
+    /// Collect the values of all matches into an array.
+    #[serde(default)]
+    pub repeated: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    #[default]
+    String,
+    Integer,
+    Float,
+    Boolean,
+    /// Normalized to RFC 3339 in UTC, like `event_timestamp`.
+    Timestamp,
+}
+
+fn default_origin() -> Origin {
+    Origin::Vrt
+}
+
+fn default_timestamp_path() -> String {
+    String::from("timestamp")
+}
+
+/// For the errors of `Mapping::load`.
+const FILE: &str = "mapping file";
+
+fn invalid(msg: String) -> ConfigFileError {
+    ConfigFileError::invalid(FILE, msg)
+}
+
+impl Mapping {
+    /// Load a mapping file, in the format given by its extension.
+    pub fn load(path: &Path) -> Result<Mapping, ConfigFileError> {
+        let mapping: Mapping = config_file::load(path, FILE)?;
+        mapping.validate()?;
+        Ok(mapping)
+    }
+
+    pub fn from_yaml(content: &str) -> Result<Mapping, ConfigFileError> {
+        config_file::from_yaml(content, FILE)
+    }
+
+    fn validate(&self) -> Result<(), ConfigFileError> {
+        for (root_tag, event) in &self.events {
+            if event.timestamp.is_empty() {
+                return Err(invalid(format!("{}: empty timestamp path", root_tag)));
+            }
+            for (name, field) in &event.fields {
+                if RESERVED.contains(&name.as_str()) {
+                    return Err(invalid(format!("{}: field name `{}` is reserved", root_tag, name)));
+                }
+                if field.path.is_empty() {
+                    return Err(invalid(format!("{}: empty path for field `{}`", root_tag, name)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the origins are VRT, meemoo or partners declared in the
+    /// origin rules.
+    pub fn check_origins(&self, origins: &OriginRules) -> Result<(), ConfigFileError> {
+        for (root_tag, event) in &self.events {
+            if !origins.is_known(&event.origin) {
+                return Err(invalid(format!(
+                    "{}: unknown partner `{}`, declare it in ORIGIN_RULES_FILE", root_tag, event.origin.to_str(),
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The root tags of the declared event types, without their namespace.
+    pub fn root_tags(&self) -> impl Iterator<Item = &str> {
+        self.events.keys().map(|key| match key.rfind('}') {
+            Some(end) if key.starts_with('{') => &key[end + 1..],
+            _ => key.as_str(),
+        })
+    }
+
+    /// The mapping for a root element: declared with its namespace, in
+    /// Clark notation (`{http://example.com/ns}localName`), or with just its
+    /// local name for any namespace.
+    pub fn get(&self, namespace: Option<&str>, root_tag: &str) -> Option<&EventMapping> {
+        namespace
+            .and_then(|namespace| self.events.get(&format!("{{{}}}{}", namespace, root_tag)))
+            .or_else(|| self.events.get(root_tag))
+    }
+}
+
+impl EventMapping {
+    /// Transform a message with this mapping.
+    pub fn parse(&self, xml: &Element, body: &str) -> Result<MappedEvent, TransformError> {
+        let event = &xml.name;
+        let event_timestamp = select(xml, &self.timestamp).into_iter().next()
+            .ok_or_else(|| TransformError::MissingField { event: event.clone(), field: self.timestamp.clone() })?;
+        let event_timestamp = timestamp::parse(&event_timestamp).ok_or_else(|| TransformError::InvalidTimestamp {
+            event: event.clone(),
+            value: event_timestamp.clone(),
+        })?;
+        let mut fields = BTreeMap::new();
+        for (name, field) in &self.fields {
+            if let Some(value) = field.value(event, name, xml)? {
+                fields.insert(name.clone(), value);
+            }
+        }
+        Ok(MappedEvent {
+            event_name: event.clone(),
+            event_namespace: xml.namespace.clone(),
+            event_timestamp,
+            event_handle_timestamp: Utc::now().to_rfc3339(),
+            fields,
+            event_payload: body.to_string(),
+            origin: self.origin.to_str(),
+        })
+    }
+}
+
+impl FieldMapping {
+    /// The value of field `name` in `xml`, a message of type `event`.
+    fn value(&self, event: &str, name: &str, xml: &Element) -> Result<Option<Value>, TransformError> {
+        let raw = select(xml, &self.path);
+        if raw.is_empty() {
+            if self.required {
+                return Err(TransformError::MissingField { event: event.to_string(), field: self.path.clone() });
+            }
+            return Ok(self.default.clone());
+        }
+        let mut values = raw.iter().map(|raw| self.convert(event, name, raw));
+        if self.repeated {
+            values.collect::<Result<Vec<Value>, _>>().map(|values| Some(Value::Array(values)))
+        } else {
+            values.next().transpose()
+        }
+    }
+
+    fn convert(&self, event: &str, name: &str, raw: &str) -> Result<Value, TransformError> {
+        let invalid = |kind| TransformError::MalformedXml(
+            format!("{}: invalid {} `{}` for field `{}`", event, kind, raw, name)
+        );
+        match self.kind {
+            FieldType::String => Ok(Value::from(raw)),
+            FieldType::Integer => raw.parse::<i64>().map(Value::from).map_err(|_| invalid("integer")),
+            FieldType::Float => raw.parse::<f64>().ok()
+                .and_then(serde_json::Number::from_f64)
+                .map(Value::Number)
+                .ok_or_else(|| invalid("float")),
+            FieldType::Boolean => match raw {
+                "true" | "1" => Ok(Value::Bool(true)),
+                "false" | "0" => Ok(Value::Bool(false)),
+                _ => Err(invalid("boolean")),
+            },
+            FieldType::Timestamp => match timestamp::parse(raw) {
+                Some(parsed) => Ok(Value::from(timestamp::format(&parsed))),
+                None => Err(TransformError::InvalidTimestamp { event: event.to_string(), value: raw.to_string() }),
+            },
+        }
+    }
+}
+
+/// The non-empty values at `path` in `root`, see the module docs.
+pub fn select(root: &Element, path: &str) -> Vec<String> {
+    let mut segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty() && *segment != ".").collect();
+    let attribute = match segments.last() {
+        Some(last) if last.starts_with('@') => segments.pop().map(|last| local_name(&last[1..])),
+        _ => None,
+    };
+    let mut elements = vec![root];
+    for segment in segments {
+        let segment = local_name(segment);
+        elements = elements.into_iter()
+            .flat_map(|element| element.children.iter().filter_map(XMLNode::as_element))
+            .filter(|child| segment == "*" || child.name == segment)
+            .collect();
+    }
+    elements.into_iter()
+        .filter_map(|element| match attribute {
+            Some(attribute) => element.attributes.get(attribute).map(|value| value.trim().to_string()),
+            None => element.get_text().map(|text| text.trim().to_string()),
+        })
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+/// Ignore the prefix: namespaces are tolerated, not checked.
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+/// An event of a type declared in the mapping file.
+#[derive(Serialize, Debug)]
+pub struct MappedEvent {
+    event_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event_namespace: Option<String>,
+    #[serde(with = "timestamp")]
+    event_timestamp: DateTime<Utc>,
+    event_handle_timestamp: String,
+    #[serde(flatten)]
+    fields: BTreeMap<String, Value>,
+    event_payload: String,
+    origin: String,
+}
+
+xml_event!(MappedEvent);
+
+impl VrtEvent for MappedEvent {
+    /// Mapped events are parsed by their `EventMapping`: without one, the
+    /// root tag is unknown.
+    fn parse(xml: &Element, _body: &str) -> Result<MappedEvent, TransformError> {
+        Err(TransformError::UnknownRootTag(xml.name.clone()))
+    }
+
+    xml_event!(@methods);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_file::ConfigFileErrorKind;
+    use serde_json::json;
+
+    const BODY: &str = r##"<subtitleAvailableEvent>
+  <timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <mediaId>AB00112233</mediaId>
+  <subtitle language="nl" lines="412"><path>/subs/AB00112233.srt</path></subtitle>
+  <subtitle language="en" lines="398"><path>/subs/AB00112233_en.srt</path></subtitle>
+  <live>false</live>
+</subtitleAvailableEvent>"##;
+
+    fn mapping() -> Mapping {
+        Mapping::from_yaml(r#"
+events:
+  subtitleAvailableEvent:
+    fields:
+      media_id: { path: mediaId, required: true }
+      languages: { path: subtitle/@language, repeated: true }
+      first_subtitle_lines: { path: subtitle/@lines, type: integer }
+      live: { path: live, type: boolean }
+      duration: { path: duration, type: float, default: 0.0 }
+      program_id: { path: metadata/programId }
+"#).unwrap()
+    }
+
+    #[test]
+    fn test_mapped_event() {
+        let xml = Element::parse(BODY.as_bytes()).unwrap();
+        let event = mapping().get(None, "subtitleAvailableEvent").unwrap().parse(&xml, BODY).unwrap();
+        let json: Value = serde_json::from_str(&event.to_json().unwrap()).unwrap();
+
+        assert_eq!(event.media_id(), Some("AB00112233"));
+        assert_eq!(event.origin(), Origin::Vrt);
+        assert_eq!(json["event_name"], "subtitleAvailableEvent");
+        assert_eq!(json["event_timestamp"], "2021-02-03T19:21:02.032Z");
+        assert_eq!(json["languages"], json!(["nl", "en"]));
+        assert_eq!(json["first_subtitle_lines"], 412);
+        assert_eq!(json["live"], false);
+        assert_eq!(json["duration"], 0.0);
+        assert!(!event.fields().contains_key("program_id"));
+        assert_eq!(json["origin"], "vrt");
+    }
+
+    #[test]
+    fn test_required_and_invalid_fields() {
+        let mapping = mapping();
+        let event = mapping.get(None, "subtitleAvailableEvent").unwrap();
+        let body = "<subtitleAvailableEvent><timestamp>2021-02-03T20:21:02Z</timestamp></subtitleAvailableEvent>";
+        match event.parse(&Element::parse(body.as_bytes()).unwrap(), body) {
+            Err(TransformError::MissingField { field, .. }) => assert_eq!(field, "mediaId"),
+            other => panic!("expected MissingField, got {:?}", other),
+        }
+        let body = BODY.replace("lines=\"412\"", "lines=\"many\"");
+        let result = event.parse(&Element::parse(body.as_bytes()).unwrap(), &body);
+        assert!(matches!(result, Err(TransformError::MalformedXml(_))));
+    }
+
+    #[test]
+    fn test_select() {
+        let xml = Element::parse(BODY.as_bytes()).unwrap();
+        assert_eq!(select(&xml, "mediaId"), vec!["AB00112233"]);
+        assert_eq!(select(&xml, "*/path").len(), 2);
+        assert_eq!(select(&xml, "subtitle/@lines"), vec!["412", "398"]);
+        assert!(select(&xml, "missing/path").is_empty());
+
+        let body = r#"<ns2:event xmlns:ns2="urn:vrt:events" xmlns:m="urn:vrt:media">
+  <ns2:mediaId>AB00112233</ns2:mediaId><m:file m:type="mxf">AB00112233.mxf</m:file>
+</ns2:event>"#;
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        assert_eq!(select(&xml, "mediaId"), vec!["AB00112233"]);
+        assert_eq!(select(&xml, "ns2:mediaId"), vec!["AB00112233"]);
+        assert_eq!(select(&xml, "m:file/@m:type"), vec!["mxf"]);
+    }
+
+    #[test]
+    fn test_namespaced_mapping() {
+        let mapping = Mapping::from_yaml(r#"
+events:
+  subtitleAvailableEvent: { origin: vrt }
+  "{urn:meemoo:events}subtitleAvailableEvent": { origin: meemoo }
+"#).unwrap();
+        let origin = |namespace| mapping.get(namespace, "subtitleAvailableEvent").unwrap().origin.clone();
+        assert_eq!(origin(None), Origin::Vrt);
+        assert_eq!(origin(Some("urn:vrt:events")), Origin::Vrt);
+        assert_eq!(origin(Some("urn:meemoo:events")), Origin::Meemoo);
+        assert_eq!(mapping.root_tags().collect::<Vec<_>>(), vec!["subtitleAvailableEvent", "subtitleAvailableEvent"]);
+
+        let body = r#"<e:subtitleAvailableEvent xmlns:e="urn:meemoo:events">
+  <e:timestamp>2021-02-03T20:21:02Z</e:timestamp>
+</e:subtitleAvailableEvent>"#;
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        let event = mapping.get(xml.namespace.as_deref(), &xml.name).unwrap().parse(&xml, body).unwrap();
+        assert!(event.to_json().unwrap().contains(r#""event_namespace":"urn:meemoo:events""#));
+    }
+
+    #[test]
+    fn test_toml_and_validation() {
+        let mapping: Mapping = toml::from_str(r#"
+[events.subtitleAvailableEvent]
+origin = "meemoo"
+[events.subtitleAvailableEvent.fields.media_id]
+path = "mediaId"
+required = true
+"#).unwrap();
+        assert_eq!(mapping.get(None, "subtitleAvailableEvent").unwrap().origin, Origin::Meemoo);
+        assert!(mapping.validate().is_ok());
+
+        let reserved = Mapping::from_yaml("events: { someEvent: { fields: { origin: { path: origin } } } }").unwrap();
+        assert!(matches!(reserved.validate(), Err(ConfigFileError { kind: ConfigFileErrorKind::Invalid(_), .. })));
+        assert!(Mapping::from_yaml("events: { someEvent: { field: {} } }").is_err());
+    }
+
+    #[test]
+    fn test_partner_origin() {
+        let mapping = Mapping::from_yaml("events: { subtitleAvailableEvent: { origin: rtbf } }").unwrap();
+        assert_eq!(
+            mapping.check_origins(&OriginRules::default()).unwrap_err().to_string(),
+            "Invalid mapping file: subtitleAvailableEvent: unknown partner `rtbf`, declare it in ORIGIN_RULES_FILE"
+        );
+        let origins = OriginRules { partners: vec!["rtbf".to_string()], ..OriginRules::default() };
+        assert!(mapping.check_origins(&origins).is_ok());
+    }
+
+    #[test]
+    fn test_example_mapping() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("mapping.example.yaml");
+        let mapping = Mapping::load(&path).unwrap();
+        assert!(!mapping.events.is_empty());
+    }
+}
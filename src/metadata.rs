@@ -0,0 +1,154 @@
+//! Typed model of the metadata block carried by `getMetadataResponse` and
+//! `metadataUpdatedEvent`.
+
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::Value;
+use xmltree::{Element, XMLNode};
+
+/// A text value with an optional qualifier, eg. a title of type `original`
+/// or a date of type `broadcast`.
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+pub struct Qualified {
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    pub value: String,
+}
+
+#[derive(Serialize, JsonSchema, Debug, Default, PartialEq)]
+pub struct Metadata {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub titles: Vec<Qualified>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub descriptions: Vec<Qualified>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dates: Vec<Qualified>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub program_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub program_title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub series_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub series_title: Option<String>,
+    /// All other fields, keyed by their (dot-separated) element path.
+    /// Repeated fields become arrays.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, Value>,
+}
+
+fn text(element: &Element) -> Option<String> {
+    element.get_text()
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+}
+
+/// The qualifier of a field: its `type` attribute, or what remains of the
+/// element name, eg. `broadcast` for `broadcastDate`.
+fn qualifier(element: &Element, suffix_len: usize) -> Option<String> {
+    if let Some(kind) = element.attributes.get("type") {
+        return Some(kind.clone());
+    }
+    let name = &element.name;
+    let prefix = &name[..name.len() - suffix_len];
+    if prefix.is_empty() {
+        None
+    } else {
+        Some(prefix.to_string())
+    }
+}
+
+impl Metadata {
+    /// Extract the metadata from the `<metadata>` child of an event, if any.
+    pub fn from_event(xml: &Element) -> Option<Metadata> {
+        xml.get_child("metadata").map(Metadata::from_xml)
+    }
+
+    /// Build the metadata model from a `<metadata>` element.
+    pub fn from_xml(xml: &Element) -> Metadata {
+        let mut metadata = Metadata::default();
+        for child in xml.children.iter().filter_map(XMLNode::as_element) {
+            metadata.add(child);
+        }
+        metadata
+    }
+
+    fn add(&mut self, element: &Element) {
+        let name = element.name.to_lowercase();
+        let value = match text(element) {
+            Some(value) => value,
+            // Not a leaf, or empty: keep nested fields as extra fields.
+            None => return self.add_extra(&element.name, element),
+        };
+        let qualified = |suffix| Qualified { kind: qualifier(element, suffix), value: value.clone() };
+        match name.as_str() {
+            "programid" | "programmeid" => self.program_id = Some(value),
+            "programtitle" | "programname" | "programmetitle" => self.program_title = Some(value),
+            "seriesid" => self.series_id = Some(value),
+            "seriestitle" | "seriesname" => self.series_title = Some(value),
+            _ if name.ends_with("title") => self.titles.push(qualified("title".len())),
+            _ if name.ends_with("description") => {
+                self.descriptions.push(qualified("description".len()))
+            },
+            _ if name.ends_with("date") => self.dates.push(qualified("date".len())),
+            _ => self.add_extra(&element.name, element),
+        }
+    }
+
+    fn add_extra(&mut self, path: &str, element: &Element) {
+        let children: Vec<&Element> = element.children.iter()
+            .filter_map(XMLNode::as_element)
+            .collect();
+        if children.is_empty() {
+            let value = Value::String(text(element).unwrap_or_default());
+            match self.extra.get_mut(path) {
+                Some(Value::Array(values)) => values.push(value),
+                Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+                None => {
+                    self.extra.insert(path.to_string(), value);
+                },
+            }
+            return;
+        }
+        for child in children {
+            self.add_extra(&format!("{}.{}", path, child.name), child);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_xml() {
+        let xml = Element::parse(r##"<metadata>
+  <title type="original">De Ideale Wereld</title>
+  <subTitle>Aflevering 12</subTitle>
+  <description>Satirisch actualiteitenprogramma</description>
+  <broadcastDate>2021-02-03</broadcastDate>
+  <programId>P0123</programId>
+  <seriesId>S0042</seriesId>
+  <seriesName>De Ideale Wereld</seriesName>
+  <keyword>satire</keyword>
+  <keyword>humor</keyword>
+  <rights><owner>VRT</owner></rights>
+</metadata>"##.as_bytes()).unwrap();
+
+        let metadata = Metadata::from_xml(&xml);
+
+        assert_eq!(metadata.titles, vec![
+            Qualified { kind: Some("original".to_string()), value: "De Ideale Wereld".to_string() },
+            Qualified { kind: Some("sub".to_string()), value: "Aflevering 12".to_string() },
+        ]);
+        assert_eq!(metadata.descriptions[0].kind, None);
+        assert_eq!(metadata.dates[0].kind.as_deref(), Some("broadcast"));
+        assert_eq!(metadata.program_id.as_deref(), Some("P0123"));
+        assert_eq!(metadata.series_id.as_deref(), Some("S0042"));
+        assert_eq!(metadata.series_title.as_deref(), Some("De Ideale Wereld"));
+        assert_eq!(metadata.extra["keyword"], serde_json::json!(["satire", "humor"]));
+        assert_eq!(metadata.extra["rights.owner"], serde_json::json!("VRT"));
+    }
+}
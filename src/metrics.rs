@@ -0,0 +1,179 @@
+//! Prometheus metrics, served on `/metrics` by the `server` module.
+
+use std::collections::BTreeSet;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Encoder, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+use crate::supervisor::ConnectionState;
+use crate::{EventType, TransformError};
+
+lazy_static! {
+    static ref MESSAGES: IntCounterVec = register_int_counter_vec!(
+        "amqp2elastic_messages_total",
+        "Messages consumed from the input queue, by root tag and result.",
+        &["root_tag", "result"]
+    ).unwrap();
+    static ref PUBLISH_DURATION: HistogramVec = register_histogram_vec!(
+        "amqp2elastic_publish_duration_seconds",
+        "Time until the output accepted a document: the broker's confirm, or the bulk request to Elasticsearch.",
+        &["output"]
+    ).unwrap();
+    static ref PREFETCH_COUNT: IntGauge = register_int_gauge!(
+        "amqp2elastic_prefetch_count",
+        "The configured prefetch count on the input queue."
+    ).unwrap();
+    static ref IN_FLIGHT: IntGauge = register_int_gauge!(
+        "amqp2elastic_in_flight_deliveries",
+        "Deliveries transformed but not yet acked, awaiting a confirm or a bulk request."
+    ).unwrap();
+    static ref CONNECTION_STATE: IntGaugeVec = register_int_gauge_vec!(
+        "amqp2elastic_connection_state",
+        "1 for the current state of the connection to the broker, 0 for the others.",
+        &["state"]
+    ).unwrap();
+    /// Root tags of the event types from the mapping file, counted by name
+    /// like the built-in ones.
+    static ref MAPPED_ROOT_TAGS: RwLock<BTreeSet<String>> = RwLock::new(BTreeSet::new());
+}
+
+const CONNECTION_STATES: [ConnectionState; 5] = [
+    ConnectionState::Connecting,
+    ConnectionState::Connected,
+    ConnectionState::Consuming,
+    ConnectionState::Disconnected,
+    ConnectionState::Closed,
+];
+
+/// What eventually happened to a consumed message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// Transformed, and accepted by the output.
+    Processed,
+    /// Transformed, but refused by the output and dead-lettered.
+    Rejected,
+    /// Transformed, but the output was unavailable: redelivered after a
+    /// delay.
+    Retried,
+    /// Not a registered event type.
+    UnknownType,
+    /// Not valid UTF-8, XML or a valid instance of its event type.
+    ParseError,
+    /// Its document doesn't match the schema, with
+    /// `SCHEMA_VALIDATION=reject`.
+    SchemaViolation,
+}
+
+impl Outcome {
+    pub fn of(err: &TransformError) -> Outcome {
+        match err {
+            TransformError::UnknownRootTag(_) => Outcome::UnknownType,
+            TransformError::SchemaViolation { .. } => Outcome::SchemaViolation,
+            _ => Outcome::ParseError,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Processed => "processed",
+            Outcome::Rejected => "rejected",
+            Outcome::Retried => "retried",
+            Outcome::UnknownType => "unknown_type",
+            Outcome::ParseError => "parse_error",
+            Outcome::SchemaViolation => "schema_violation",
+        }
+    }
+}
+
+/// Register all metrics, so they are exported before their first update.
+pub fn init() {
+    lazy_static::initialize(&MESSAGES);
+    lazy_static::initialize(&PUBLISH_DURATION);
+    lazy_static::initialize(&PREFETCH_COUNT);
+    lazy_static::initialize(&IN_FLIGHT);
+    set_connection_state(ConnectionState::Disconnected);
+}
+
+/// Count messages with these root tags by name too, besides the built-in
+/// event types: those of the mapping file.
+pub fn register_root_tags<'a>(root_tags: impl IntoIterator<Item = &'a str>) {
+    MAPPED_ROOT_TAGS.write().unwrap().extend(root_tags.into_iter().map(String::from));
+}
+
+/// Count a message with the given root tag. Unregistered root tags are
+/// counted as `other`, so the label set stays bounded.
+pub fn message(root_tag: &str, outcome: Outcome) {
+    let mapped = MAPPED_ROOT_TAGS.read().unwrap();
+    let root_tag = match EventType::lookup(root_tag) {
+        Some(event_type) => event_type.root_tag,
+        None if mapped.contains(root_tag) => root_tag,
+        None => "other",
+    };
+    MESSAGES.with_label_values(&[root_tag, outcome.as_str()]).inc();
+}
+
+/// Record how long `output` (`amqp` or `elasticsearch`) took to accept a
+/// document.
+pub fn observe_publish(output: &str, duration: Duration) {
+    PUBLISH_DURATION.with_label_values(&[output]).observe(duration.as_secs_f64());
+}
+
+pub fn set_prefetch_count(prefetch_count: u16) {
+    PREFETCH_COUNT.set(i64::from(prefetch_count));
+}
+
+pub fn set_in_flight(in_flight: usize) {
+    IN_FLIGHT.set(in_flight as i64);
+}
+
+pub fn set_connection_state(current: ConnectionState) {
+    for state in CONNECTION_STATES.iter() {
+        let value = if *state == current { 1 } else { 0 };
+        CONNECTION_STATE.with_label_values(&[&format!("{:?}", state).to_lowercase()]).set(value);
+    }
+}
+
+/// All metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        init();
+        message("essenceArchivedEvent", Outcome::Processed);
+        message("someNewEvent", Outcome::UnknownType);
+        register_root_tags(vec!["subtitleReadyEvent"]);
+        message("subtitleReadyEvent", Outcome::Processed);
+        set_connection_state(ConnectionState::Consuming);
+        observe_publish("amqp", Duration::from_millis(3));
+
+        let text = render();
+
+        assert!(text.contains(r#"amqp2elastic_messages_total{result="processed",root_tag="essenceArchivedEvent"}"#));
+        assert!(text.contains(r#"amqp2elastic_messages_total{result="unknown_type",root_tag="other"}"#));
+        assert!(text.contains(r#"amqp2elastic_messages_total{result="processed",root_tag="subtitleReadyEvent"}"#));
+        assert!(text.contains(r#"amqp2elastic_connection_state{state="consuming"}"#));
+        assert!(text.contains(r#"amqp2elastic_publish_duration_seconds_count{output="amqp"}"#));
+        assert!(text.contains("amqp2elastic_in_flight_deliveries 0"));
+    }
+
+    #[test]
+    fn test_outcome_of_error() {
+        assert_eq!(Outcome::of(&TransformError::UnknownRootTag("x".to_string())), Outcome::UnknownType);
+        assert_eq!(Outcome::of(&TransformError::MalformedXml("x".to_string())), Outcome::ParseError);
+        let violation = TransformError::SchemaViolation { event: "x".to_string(), errors: Vec::new() };
+        assert_eq!(Outcome::of(&violation), Outcome::SchemaViolation);
+    }
+}
@@ -0,0 +1,233 @@
+//! Which partner sent a message (`ORIGIN_RULES_FILE`), instead of the origin
+//! fixed per event type, for when other broadcasters send the same events
+//! through our broker.
+//!
+//! The rules are tried in order; the first that matches the message's AMQP
+//! `app_id`, `user_id`, a header or the queue it came from decides. Without
+//! a match, the event type's default from the file applies, and otherwise
+//! its built-in origin. See `origins.example.yaml`.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use amiquip::{AmqpProperties, AmqpValue};
+use serde::Deserialize;
+
+use crate::config_file::{self, ConfigFileError};
+use crate::Origin;
+
+/// Where a message came from: its AMQP properties and the queue it was
+/// consumed from, if it came from a broker.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Source<'a> {
+    pub properties: Option<&'a AmqpProperties>,
+    pub queue: Option<&'a str>,
+}
+
+impl<'a> Source<'a> {
+    pub fn amqp(properties: &'a AmqpProperties, queue: &'a str) -> Source<'a> {
+        Source { properties: Some(properties), queue: Some(queue) }
+    }
+
+    pub fn message_id(&self) -> Option<&'a str> {
+        self.properties.and_then(|properties| properties.message_id().as_deref())
+    }
+
+    fn app_id(&self) -> Option<&'a str> {
+        self.properties.and_then(|properties| properties.app_id().as_deref())
+    }
+
+    fn user_id(&self) -> Option<&'a str> {
+        self.properties.and_then(|properties| properties.user_id().as_deref())
+    }
+
+    /// The value of a string header.
+    fn header(&self, name: &str) -> Option<&'a str> {
+        let headers = self.properties.and_then(|properties| properties.headers().as_ref())?;
+        match headers.get(name)? {
+            AmqpValue::LongString(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OriginRules {
+    /// Names of the partners besides `vrt` and `meemoo`.
+    #[serde(default)]
+    pub partners: Vec<String>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// The origin of an event type (by root tag) when no rule matches.
+    #[serde(default)]
+    pub event_types: BTreeMap<String, Origin>,
+}
+
+/// Messages that match all of the given conditions are from `origin`.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub app_id: Option<String>,
+    pub user_id: Option<String>,
+    pub queue: Option<String>,
+    /// A header the message must have, with `value` if given.
+    pub header: Option<String>,
+    pub value: Option<String>,
+    /// If not given, the value of `header` is the origin.
+    pub origin: Option<Origin>,
+}
+
+/// For the errors of `OriginRules::load`.
+const FILE: &str = "origin rules file";
+
+fn invalid(msg: String) -> ConfigFileError {
+    ConfigFileError::invalid(FILE, msg)
+}
+
+impl OriginRules {
+    /// Load an origin rules file, in the format given by its extension.
+    pub fn load(path: &Path) -> Result<OriginRules, ConfigFileError> {
+        let rules: OriginRules = config_file::load(path, FILE)?;
+        rules.validate()?;
+        Ok(rules)
+    }
+
+    pub fn from_yaml(content: &str) -> Result<OriginRules, ConfigFileError> {
+        config_file::from_yaml(content, FILE)
+    }
+
+    fn validate(&self) -> Result<(), ConfigFileError> {
+        for partner in &self.partners {
+            if !matches!(Origin::from(partner.as_str()), Origin::Partner(_)) || partner.is_empty() {
+                return Err(invalid(format!("`{}` can't be a partner name", partner)));
+            }
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            let conditions = [&rule.app_id, &rule.user_id, &rule.queue, &rule.header];
+            if conditions.iter().all(|condition| condition.is_none()) {
+                return Err(invalid(format!("rule {} has no condition", i + 1)));
+            }
+            match &rule.origin {
+                Some(origin) if !self.is_known(origin) => {
+                    return Err(invalid(format!("rule {}: unknown partner `{}`", i + 1, origin.to_str())));
+                },
+                None if rule.header.is_none() => {
+                    return Err(invalid(format!("rule {} has no origin nor header", i + 1)));
+                },
+                _ => {},
+            }
+        }
+        for (event_type, origin) in &self.event_types {
+            if !self.is_known(origin) {
+                return Err(invalid(format!("{}: unknown partner `{}`", event_type, origin.to_str())));
+            }
+        }
+        Ok(())
+    }
+
+    /// VRT, meemoo, or one of the declared partners.
+    pub fn is_known(&self, origin: &Origin) -> bool {
+        match origin {
+            Origin::Partner(name) => self.partners.contains(name),
+            _ => true,
+        }
+    }
+
+    /// The origin of a message of type `event_type` from `source`, if the
+    /// rules decide it.
+    pub fn origin(&self, source: &Source, event_type: &str) -> Option<Origin> {
+        for rule in &self.rules {
+            let origin = match rule.origin(source) {
+                Some(origin) => origin,
+                None => continue,
+            };
+            if self.is_known(&origin) {
+                return Some(origin);
+            }
+            warn!("Ignoring unknown origin `{}` of {}", origin.to_str(), event_type);
+        }
+        self.event_types.get(event_type).cloned()
+    }
+}
+
+impl Rule {
+    fn origin(&self, source: &Source) -> Option<Origin> {
+        let matches = |expected: &Option<String>, actual: Option<&str>| match expected {
+            Some(expected) => actual == Some(expected.as_str()),
+            None => true,
+        };
+        if !matches(&self.app_id, source.app_id())
+            || !matches(&self.user_id, source.user_id())
+            || !matches(&self.queue, source.queue) {
+            return None;
+        }
+        let header = match &self.header {
+            Some(name) => match source.header(name) {
+                Some(header) if matches(&self.value, Some(header)) => Some(header),
+                _ => return None,
+            },
+            None => None,
+        };
+        self.origin.clone().or_else(|| header.map(Origin::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amiquip::FieldTable;
+
+    fn rules() -> OriginRules {
+        OriginRules::load(Path::new("origins.example.yaml")).unwrap()
+    }
+
+    fn properties(app_id: Option<&str>, header: Option<&str>) -> AmqpProperties {
+        let mut properties = AmqpProperties::default();
+        if let Some(app_id) = app_id {
+            properties = properties.with_app_id(app_id.to_string());
+        }
+        if let Some(header) = header {
+            let mut headers = FieldTable::new();
+            headers.insert("x-origin".to_string(), AmqpValue::LongString(header.to_string()));
+            properties = properties.with_headers(headers);
+        }
+        properties
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules = rules();
+        let rtbf = Origin::Partner("rtbf".to_string());
+        let from = |app_id, header, queue| {
+            let properties = properties(app_id, header);
+            rules.origin(&Source::amqp(&properties, queue), "essenceLinkedEvent")
+        };
+        assert_eq!(from(Some("rtbf-mam"), None, "vrt_events_xml"), Some(rtbf.clone()));
+        assert_eq!(from(None, None, "rtbf_events_xml"), Some(rtbf));
+        assert_eq!(from(None, Some("npo"), "vrt_events_xml"), Some(Origin::Partner("npo".to_string())));
+        // Not a declared partner: the next rule, or none
+        assert_eq!(from(None, Some("bbc"), "vrt_events_xml"), None);
+        assert_eq!(from(None, None, "vrt_events_xml"), None);
+    }
+
+    #[test]
+    fn test_event_type_default() {
+        let rules = rules();
+        assert_eq!(rules.origin(&Source::default(), "essenceArchivedEvent"), Some(Origin::Meemoo));
+        assert_eq!(rules.origin(&Source::default(), "essenceLinkedEvent"), None);
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let invalid = |yaml: &str| OriginRules::from_yaml(yaml).unwrap().validate().unwrap_err().to_string();
+        assert_eq!(invalid("rules:\n  - origin: vrt"), "Invalid origin rules file: rule 1 has no condition");
+        assert_eq!(
+            invalid("rules:\n  - queue: q\n    origin: rtbf"),
+            "Invalid origin rules file: rule 1: unknown partner `rtbf`"
+        );
+        assert_eq!(invalid("rules:\n  - queue: q"), "Invalid origin rules file: rule 1 has no origin nor header");
+        assert_eq!(invalid("partners: [vrt]"), "Invalid origin rules file: `vrt` can't be a partner name");
+        assert!(OriginRules::from_yaml("rules:\n  - queue: q\n    partner: vrt").is_err());
+    }
+}
@@ -0,0 +1,107 @@
+//! Details of the subtitle (OT, "ondertiteling") availability events, which
+//! VRT sends nested inside the event rather than as direct children.
+
+use xmltree::{Element, XMLNode};
+
+/// The fields of `closedOtAvailableEvent` and `openOtAvailableEvent` we want
+/// to correlate on.
+#[derive(Debug, Default, PartialEq)]
+pub struct OtInfo {
+    pub media_id: Option<String>,
+    pub ot_type: Option<String>,
+    pub subtitle_path: Option<String>,
+    pub subtitle_format: Option<String>,
+}
+
+/// Where the OT element can be, below the event element.
+const OT: &[&[&str]] = &[&["ot"], &["subtitle", "ot"]];
+/// Where the element describing the subtitle file can be: its own `file`
+/// element, or the OT element itself.
+const FILE: &[&[&str]] = &[&["file"], &["subtitle", "file"], &["ot"], &["subtitle", "ot"]];
+
+/// The element at `path` (of element names) below `xml`.
+fn at<'a>(xml: &'a Element, path: &[&str]) -> Option<&'a Element> {
+    path.iter().try_fold(xml, |element, name| element.get_child(*name))
+}
+
+/// The text of the first direct child of `xml` named one of `names`.
+fn child_text(xml: &Element, names: &[&str]) -> Option<String> {
+    xml.children.iter()
+        .filter_map(XMLNode::as_element)
+        .filter(|child| names.contains(&child.name.as_str()))
+        .filter_map(|child| child.get_text().map(|text| text.trim().to_string()))
+        .find(|text| !text.is_empty())
+}
+
+/// The first of `names` found as a child of the elements at `paths`.
+fn find_text(xml: &Element, paths: &[&[&str]], names: &[&str]) -> Option<String> {
+    paths.iter()
+        .filter_map(|path| at(xml, path))
+        .find_map(|element| child_text(element, names))
+}
+
+impl OtInfo {
+    pub fn from_xml(xml: &Element) -> OtInfo {
+        let media_id = ["mediaId", "mediaid", "media_id"];
+        OtInfo {
+            media_id: child_text(xml, &media_id).or_else(|| find_text(xml, OT, &media_id)),
+            ot_type: find_text(xml, OT, &["otType", "ottype", "ot_type"]),
+            subtitle_path: find_text(xml, FILE, &["path", "destinationPath", "subtitlePath", "location"]),
+            subtitle_format: find_text(xml, FILE, &["format", "subtitleFormat", "fileFormat"]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_xml_nested() {
+        let xml = Element::parse(r##"<openOtAvailableEvent>
+  <timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <subtitle>
+    <ot>
+      <mediaId>AB00112233</mediaId>
+      <otType>OPEN</otType>
+    </ot>
+    <file>
+      <path>/subtitles/AB00112233_open.srt</path>
+      <format>srt</format>
+    </file>
+  </subtitle>
+</openOtAvailableEvent>"##.as_bytes()).unwrap();
+
+        assert_eq!(OtInfo::from_xml(&xml), OtInfo {
+            media_id: Some("AB00112233".to_string()),
+            ot_type: Some("OPEN".to_string()),
+            subtitle_path: Some("/subtitles/AB00112233_open.srt".to_string()),
+            subtitle_format: Some("srt".to_string()),
+        });
+    }
+
+    #[test]
+    fn test_from_xml_elsewhere() {
+        // Elements with these names outside the OT and file elements are not
+        // about the subtitle
+        let xml = Element::parse(r##"<closedOtAvailableEvent>
+  <timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <mediaId>AB00112233</mediaId>
+  <source><path>/video/AB00112233.mxf</path><format>mxf</format></source>
+  <ot><otType>CLOSED</otType><location>/subtitles/AB00112233.stl</location></ot>
+</closedOtAvailableEvent>"##.as_bytes()).unwrap();
+
+        assert_eq!(OtInfo::from_xml(&xml), OtInfo {
+            media_id: Some("AB00112233".to_string()),
+            ot_type: Some("CLOSED".to_string()),
+            subtitle_path: Some("/subtitles/AB00112233.stl".to_string()),
+            subtitle_format: None,
+        });
+    }
+
+    #[test]
+    fn test_from_xml_missing() {
+        let xml = Element::parse("<closedOtAvailableEvent><timestamp>t</timestamp></closedOtAvailableEvent>".as_bytes()).unwrap();
+        assert_eq!(OtInfo::from_xml(&xml), OtInfo::default());
+    }
+}
@@ -0,0 +1,207 @@
+//! Delayed redelivery of messages that failed for a transient reason (the
+//! output was unavailable, or did not confirm in time).
+//!
+//! Such a message is republished to a retry queue, whose TTL dead-letters it
+//! back into the input queue once the delay has passed (the TTL + DLX
+//! pattern). There is a retry queue per configured delay. The attempts so far
+//! are counted in the `x-retry-count` header; once they are used up, the
+//! message is dead-lettered like a permanent failure.
+
+use std::time::Duration;
+
+use amiquip::{AmqpProperties, AmqpValue, FieldTable};
+
+use crate::{Config, TransformError};
+
+pub const RETRY_COUNT_HEADER: &str = "x-retry-count";
+
+/// Why a delivery could not be handled, by whether trying again later may
+/// help.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Failure {
+    /// The output was unavailable, or didn't accept the document in time.
+    Transient { kind: &'static str, message: String },
+    /// The message itself is wrong: malformed, of an unknown type, or
+    /// refused by the output.
+    Permanent { kind: &'static str, message: String },
+}
+
+impl Failure {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Failure::Transient { kind, .. } | Failure::Permanent { kind, .. } => kind,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Failure::Transient { message, .. } | Failure::Permanent { message, .. } => message,
+        }
+    }
+
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Failure::Transient { .. })
+    }
+}
+
+/// Transforming again gives the same result: always permanent.
+impl From<&TransformError> for Failure {
+    fn from(err: &TransformError) -> Failure {
+        Failure::Permanent { kind: err.kind(), message: err.to_string() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedeliverySchedule {
+    /// The delay before each retry; the last one is repeated for further
+    /// attempts.
+    pub delays: Vec<Duration>,
+    /// Retries before dead-lettering; 0 disables delayed redelivery
+    /// (transient failures are requeued right away).
+    pub max_attempts: u32,
+}
+
+impl RedeliverySchedule {
+    pub fn from_config(config: &Config) -> RedeliverySchedule {
+        RedeliverySchedule {
+            delays: config.amqp_redelivery_delays_ms.iter().map(|ms| Duration::from_millis(*ms)).collect(),
+            max_attempts: config.amqp_redelivery_max_attempts,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_attempts > 0 && !self.delays.is_empty()
+    }
+
+    /// The delay before retry number `attempt` (starting at 1), or None if
+    /// the attempts are used up.
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if !self.is_enabled() || attempt == 0 || attempt > self.max_attempts {
+            return None;
+        }
+        let index = (attempt as usize - 1).min(self.delays.len() - 1);
+        Some(self.delays[index])
+    }
+
+    /// The retry queues for `in_queue` with their delays, one per distinct
+    /// delay that can be used.
+    pub fn queues(&self, in_queue: &str) -> Vec<(String, Duration)> {
+        if !self.is_enabled() {
+            return Vec::new();
+        }
+        let mut delays = self.delays[..self.delays.len().min(self.max_attempts as usize)].to_vec();
+        delays.dedup();
+        delays.into_iter().map(|delay| (queue_name(in_queue, delay), delay)).collect()
+    }
+}
+
+/// The retry queue of `in_queue` for `delay`. The delay is part of the name,
+/// as the TTL of an existing queue cannot be changed.
+pub fn queue_name(in_queue: &str, delay: Duration) -> String {
+    format!("{}_retry_{}ms", in_queue, delay.as_millis())
+}
+
+/// The arguments to declare a retry queue with: messages expire after
+/// `delay`, and are then dead-lettered to `in_queue` via the default
+/// exchange.
+pub fn queue_arguments(in_queue: &str, delay: Duration) -> FieldTable {
+    let mut arguments = FieldTable::new();
+    arguments.insert("x-message-ttl".to_string(), AmqpValue::LongLongInt(delay.as_millis() as i64));
+    arguments.insert("x-dead-letter-exchange".to_string(), AmqpValue::LongString(String::new()));
+    arguments.insert("x-dead-letter-routing-key".to_string(), AmqpValue::LongString(in_queue.to_string()));
+    arguments
+}
+
+/// The number of times the message was retried already, from its
+/// `x-retry-count` header.
+pub fn retry_count(properties: &AmqpProperties) -> u32 {
+    let value = properties.headers().as_ref().and_then(|headers| headers.get(RETRY_COUNT_HEADER));
+    let count = match value {
+        Some(AmqpValue::ShortShortInt(n)) => i64::from(*n),
+        Some(AmqpValue::ShortShortUInt(n)) => i64::from(*n),
+        Some(AmqpValue::ShortInt(n)) => i64::from(*n),
+        Some(AmqpValue::ShortUInt(n)) => i64::from(*n),
+        Some(AmqpValue::LongInt(n)) => i64::from(*n),
+        Some(AmqpValue::LongUInt(n)) => i64::from(*n),
+        Some(AmqpValue::LongLongInt(n)) => *n,
+        Some(AmqpValue::LongString(s)) => s.parse().unwrap_or(0),
+        _ => 0,
+    };
+    count.clamp(0, i64::from(u32::MAX)) as u32
+}
+
+/// `properties` with the `x-retry-count` header set to `count`, keeping the
+/// other headers.
+pub fn with_retry_count(properties: &AmqpProperties, count: u32) -> AmqpProperties {
+    let mut headers = properties.headers().clone().unwrap_or_default();
+    headers.insert(RETRY_COUNT_HEADER.to_string(), AmqpValue::LongUInt(count));
+    properties.clone().with_headers(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+
+    fn schedule(delays: &[u64], max_attempts: u32) -> RedeliverySchedule {
+        RedeliverySchedule {
+            delays: delays.iter().map(|ms| Duration::from_millis(*ms)).collect(),
+            max_attempts,
+        }
+    }
+
+    #[test]
+    fn test_delays() {
+        let schedule = schedule(&[1000, 10000], 3);
+        assert_eq!(schedule.delay(1), Some(Duration::from_millis(1000)));
+        assert_eq!(schedule.delay(2), Some(Duration::from_millis(10000)));
+        assert_eq!(schedule.delay(3), Some(Duration::from_millis(10000)));
+        assert_eq!(schedule.delay(4), None);
+        assert_eq!(schedule.queues("in_q"), vec![
+            ("in_q_retry_1000ms".to_string(), Duration::from_millis(1000)),
+            ("in_q_retry_10000ms".to_string(), Duration::from_millis(10000)),
+        ]);
+    }
+
+    #[test]
+    fn test_from_config() {
+        let config = test_config(vec![
+            ("AMQP_REDELIVERY_DELAYS_MS", "1000,30000"),
+            ("AMQP_REDELIVERY_MAX_ATTEMPTS", "5"),
+        ]);
+        assert_eq!(RedeliverySchedule::from_config(&config), schedule(&[1000, 30000], 5));
+        let config = test_config(vec![]);
+        assert!(!RedeliverySchedule::from_config(&config).is_enabled());
+    }
+
+    #[test]
+    fn test_disabled() {
+        assert!(!schedule(&[1000], 0).is_enabled());
+        assert_eq!(schedule(&[1000], 0).delay(1), None);
+        assert!(!schedule(&[], 3).is_enabled());
+        assert!(schedule(&[], 3).queues("in_q").is_empty());
+        // More delays than attempts: only the used ones get a queue
+        assert_eq!(schedule(&[1000, 2000, 3000], 1).queues("in_q").len(), 1);
+    }
+
+    #[test]
+    fn test_retry_count_header() {
+        let properties = AmqpProperties::default();
+        assert_eq!(retry_count(&properties), 0);
+
+        let mut headers = FieldTable::new();
+        headers.insert("x-other".to_string(), AmqpValue::Boolean(true));
+        let properties = with_retry_count(&properties.with_headers(headers), 2);
+        assert_eq!(retry_count(&properties), 2);
+        assert_eq!(retry_count(&with_retry_count(&properties, 3)), 3);
+        assert!(properties.headers().as_ref().unwrap().contains_key("x-other"));
+    }
+
+    #[test]
+    fn test_transform_errors_are_permanent() {
+        let failure = Failure::from(&TransformError::UnknownRootTag("unknownEvent".to_string()));
+        assert!(!failure.is_transient());
+        assert_eq!(failure.kind(), "unknown_root_tag");
+        assert_eq!(failure.message(), "Unknown event type: unknownEvent");
+    }
+}
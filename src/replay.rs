@@ -0,0 +1,346 @@
+//! The `replay` subcommand: take the messages out of the dead-letter queue
+//! and run them through the current transformation again.
+//!
+//! Messages are fetched one by one with `basic.get` and held unacked, so
+//! every message is seen once. Successes are published to the output, and
+//! acked once the output accepted them; messages that still fail are
+//! requeued at the end (or moved to a parking queue, which must exist unless
+//! `AMQP_DECLARE` is set).
+
+use std::error::Error;
+use std::time::Duration;
+
+use amiquip::{Channel, Confirm, Delivery, Publish, QueueDeclareOptions};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use native_tls::TlsConnector;
+
+use crate::confirms::{ConfirmOutcome, PendingConfirms};
+use crate::connection::{open_connection, output_exchange};
+use crate::elastic::{BulkSink, ItemStatus};
+use crate::{parse_message, setting, Config, Output, Source, Transformer};
+
+pub const USAGE: &str = "Usage: amqp2elastic replay [--queue QUEUE] [--parking-queue QUEUE] [--max-count N] [--dry-run]
+
+Transform the messages in the dead-letter queue (AMQP_DLQ, or --queue) again
+and send the successes to the output. Messages that still fail stay in the
+queue, or are moved to the parking queue (AMQP_PARKING_QUEUE, or
+--parking-queue). With --dry-run, nothing is published or removed.";
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub queue: String,
+    pub parking_queue: Option<String>,
+    pub max_count: Option<usize>,
+    pub dry_run: bool,
+}
+
+impl Options {
+    /// Parse the arguments following `replay`, with defaults from `config`.
+    pub fn parse(args: &[String], config: &Config) -> Result<Options, String> {
+        let mut queue = setting(&config.amqp_dlq).map(String::from);
+        let mut parking_queue = setting(&config.amqp_parking_queue).map(String::from);
+        let mut max_count = None;
+        let mut dry_run = false;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().cloned().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--queue" => queue = Some(value()?),
+                "--parking-queue" => parking_queue = Some(value()?),
+                "--max-count" => {
+                    let count = value()?;
+                    max_count = Some(count.parse().map_err(|_| format!("Invalid --max-count {}", count))?);
+                },
+                "--dry-run" => dry_run = true,
+                other => return Err(format!("Unknown option {}", other)),
+            }
+        }
+        let queue = queue.ok_or("No dead-letter queue: set AMQP_DLQ or pass --queue")?;
+        Ok(Options { queue, parking_queue, max_count, dry_run })
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    /// Transformed and accepted by the output (or would be, in a dry run).
+    pub replayed: usize,
+    /// Still failing, moved to the parking queue.
+    pub parked: usize,
+    /// Left in the dead-letter queue: still failing, or not accepted by the
+    /// output.
+    pub left: usize,
+}
+
+/// What to do with a dead-lettered message, once the output confirms.
+enum Action {
+    /// Published the JSON document.
+    Replayed(Vec<u8>),
+    Parked,
+}
+
+/// Replay the messages in the dead-letter queue, see the module docs.
+pub fn replay(
+    config: &Config,
+    tls: Option<&TlsConnector>,
+    transformer: &Transformer,
+    options: &Options,
+) -> Result<Report, Box<dyn Error>> {
+    let mut connection = open_connection(config, tls)?;
+    let channel = connection.open_channel(None)?;
+    let queue = channel.queue_declare_passive(options.queue.as_str())?;
+    if let Some(parking_queue) = &options.parking_queue {
+        declare_parking_queue(&channel, config, options, parking_queue)?;
+    }
+    let exchange = output_exchange(&channel, config)?;
+    let (confirms, returns) = if options.dry_run {
+        (crossbeam_channel::never(), crossbeam_channel::never())
+    } else {
+        let returns = channel.listen_for_returns()?;
+        let confirms = channel.listen_for_publisher_confirms()?;
+        channel.enable_publisher_confirms()?;
+        (confirms, returns)
+    };
+    let mut pending = PendingConfirms::new(Duration::from_millis(config.amqp_confirm_timeout_ms));
+    let mut elastic = match config.output {
+        Output::Elasticsearch => Some(BulkSink::from_config(config)?),
+        Output::Amqp => None,
+    };
+    let mut report = Report::default();
+    // Deliveries to requeue at the end
+    let mut held: Vec<Delivery> = Vec::new();
+
+    let mut count = 0;
+    while Some(count) != options.max_count {
+        let delivery = match queue.get(false)? {
+            Some(get) => get.delivery,
+            None => break,
+        };
+        count += 1;
+        let result = parse_message(&delivery.body).and_then(|(xml, body)| {
+            // As if consumed from the input queue, for the origin rules
+            transformer.handle_xml(xml, body, &Source::amqp(&delivery.properties, &config.amqp_in_queue))
+        });
+        match result {
+            Ok(_) if options.dry_run => {
+                report.replayed += 1;
+                held.push(delivery);
+            },
+            Ok(document) => match elastic.as_mut() {
+                Some(sink) => {
+                    sink.push(delivery, document);
+                    if sink.is_due() {
+                        flush_to_elastic(sink, &channel, &mut report, &mut held)?;
+                    }
+                },
+                None => {
+                    exchange.publish(document.publish(config.routing_key()))?;
+                    pending.published((delivery, Action::Replayed(document.json.into_bytes())));
+                },
+            },
+            Err(err) => {
+                eprintln!("Message {} still fails: {}", count, err);
+                match &options.parking_queue {
+                    Some(parking_queue) if !options.dry_run => {
+                        // Mandatory: returned rather than dropped if the
+                        // parking queue is gone
+                        let properties = delivery.properties.clone();
+                        let publish = Publish {
+                            mandatory: true,
+                            ..Publish::with_properties(&delivery.body, parking_queue.as_str(), properties)
+                        };
+                        channel.basic_publish("", publish)?;
+                        pending.published((delivery, Action::Parked));
+                    },
+                    Some(_) => {
+                        report.parked += 1;
+                        held.push(delivery);
+                    },
+                    None => {
+                        report.left += 1;
+                        held.push(delivery);
+                    },
+                }
+            },
+        }
+    }
+
+    if let Some(sink) = elastic.as_mut() {
+        flush_to_elastic(sink, &channel, &mut report, &mut held)?;
+    }
+    let outcomes = wait_for_confirms(&mut pending, &confirms);
+    // The broker returns an unroutable message before confirming it
+    let mut returned: Vec<Vec<u8>> = returns.try_iter().map(|returned| returned.content).collect();
+    for ((delivery, action), outcome) in outcomes {
+        let body = match &action {
+            Action::Replayed(json) => json.as_slice(),
+            Action::Parked => delivery.body.as_slice(),
+        };
+        let outcome = match outcome {
+            ConfirmOutcome::Acked if take_returned(&mut returned, body) => ConfirmOutcome::Returned,
+            outcome => outcome,
+        };
+        match (outcome, action) {
+            (ConfirmOutcome::Acked, Action::Replayed(_)) => report.replayed += 1,
+            (ConfirmOutcome::Acked, Action::Parked) => report.parked += 1,
+            (ConfirmOutcome::Returned, _) => {
+                eprintln!("Publish failed: no queue took the message");
+                report.left += 1;
+                held.push(delivery);
+                continue;
+            },
+            (outcome, _) => {
+                eprintln!("Publish was {:?}", outcome);
+                report.left += 1;
+                held.push(delivery);
+                continue;
+            },
+        }
+        delivery.ack(&channel)?;
+    }
+
+    requeue(held, &channel)?;
+    connection.close()?;
+    Ok(report)
+}
+
+/// Make sure the parking queue exists before moving messages to it: declare
+/// it with `AMQP_DECLARE` (unless in a dry run), otherwise fail if missing.
+fn declare_parking_queue(
+    channel: &Channel,
+    config: &Config,
+    options: &Options,
+    parking_queue: &str,
+) -> Result<(), Box<dyn Error>> {
+    if config.amqp_declare && !options.dry_run {
+        channel.queue_declare(parking_queue, QueueDeclareOptions { durable: true, ..QueueDeclareOptions::default() })?;
+        return Ok(());
+    }
+    channel.queue_declare_passive(parking_queue)
+        .map_err(|err| format!("Parking queue q:{} is not available: {}", parking_queue, err))?;
+    Ok(())
+}
+
+/// Whether a message with `body` was returned; each return accounts for one
+/// message.
+fn take_returned(returned: &mut Vec<Vec<u8>>, body: &[u8]) -> bool {
+    match returned.iter().position(|content| content.as_slice() == body) {
+        Some(i) => {
+            returned.swap_remove(i);
+            true
+        },
+        None => false,
+    }
+}
+
+fn flush_to_elastic(
+    sink: &mut BulkSink<Delivery>,
+    channel: &Channel,
+    report: &mut Report,
+    held: &mut Vec<Delivery>,
+) -> amiquip::Result<()> {
+    for (delivery, status) in sink.flush() {
+        match status {
+            ItemStatus::Indexed => {
+                report.replayed += 1;
+                delivery.ack(channel)?;
+            },
+            ItemStatus::Retry(reason) | ItemStatus::Rejected(reason) => {
+                eprintln!("Indexing failed: {}", reason);
+                report.left += 1;
+                held.push(delivery);
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Wait until every published message was confirmed, or timed out.
+fn wait_for_confirms<T>(pending: &mut PendingConfirms<T>, confirms: &Receiver<Confirm>) -> Vec<(T, ConfirmOutcome)> {
+    let mut outcomes = Vec::new();
+    while let Some(timeout) = pending.time_until_expiry() {
+        match confirms.recv_timeout(timeout) {
+            Ok(confirm) => outcomes.extend(pending.confirm(confirm)),
+            Err(RecvTimeoutError::Timeout) => outcomes.extend(pending.expire()),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    outcomes
+}
+
+fn requeue(deliveries: Vec<Delivery>, channel: &Channel) -> amiquip::Result<()> {
+    for delivery in deliveries {
+        delivery.nack(channel, true)?;
+    }
+    Ok(())
+}
+
+/// Run the subcommand with the arguments following `replay`; returns the
+/// exit code.
+pub fn main(args: &[String], config: &Config, tls: Option<&TlsConnector>, transformer: &Transformer) -> i32 {
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return 0;
+    }
+    let options = match Options::parse(args, config) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("{}\n\n{}", msg, USAGE);
+            return 2;
+        },
+    };
+    match replay(config, tls, transformer, &options) {
+        Ok(report) => {
+            eprintln!("{}eplayed {}, parked {}, left {} in q:{}",
+                if options.dry_run { "Dry run: would have r" } else { "R" },
+                report.replayed, report.parked, report.left, options.queue);
+            if report.left > 0 { 1 } else { 0 }
+        },
+        Err(err) => {
+            eprintln!("Replay failed: {}", err);
+            2
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_options_from_config_and_args() {
+        let config = test_config(vec![("AMQP_DLQ", "vrt2elk_events_xml_dlq")]);
+        assert_eq!(Options::parse(&args(&["--max-count", "10", "--dry-run"]), &config), Ok(Options {
+            queue: "vrt2elk_events_xml_dlq".to_string(),
+            parking_queue: None,
+            max_count: Some(10),
+            dry_run: true,
+        }));
+        let options = Options::parse(&args(&["--queue", "other_dlq", "--parking-queue", "parked"]), &config).unwrap();
+        assert_eq!(options.queue, "other_dlq");
+        assert_eq!(options.parking_queue.as_deref(), Some("parked"));
+    }
+
+    #[test]
+    fn test_take_returned() {
+        let mut returned = vec![b"<a/>".to_vec(), b"<b/>".to_vec(), b"<a/>".to_vec()];
+        assert!(take_returned(&mut returned, b"<a/>"));
+        assert!(!take_returned(&mut returned, b"<c/>"));
+        assert!(take_returned(&mut returned, b"<a/>"));
+        assert!(!take_returned(&mut returned, b"<a/>"));
+        assert_eq!(returned, vec![b"<b/>".to_vec()]);
+    }
+
+    #[test]
+    fn test_options_errors() {
+        assert!(Options::parse(&[], &test_config(vec![])).is_err());
+        let config = test_config(vec![("AMQP_DLQ", "dlq")]);
+        assert!(Options::parse(&args(&["--max-count", "many"]), &config).is_err());
+        assert!(Options::parse(&args(&["--max-count"]), &config).is_err());
+        assert!(Options::parse(&args(&["--force"]), &config).is_err());
+    }
+}
@@ -0,0 +1,423 @@
+//! JSON Schemas of the documents we emit, one per event type: the contract
+//! for the consumers of the output, printed by the `schema` subcommand.
+//!
+//! The schemas are generated from the event structs (and the declarations
+//! in the mapping file). With `SCHEMA_VALIDATION`, every document is checked
+//! against the schema of its event type before it is sent, and flagged with
+//! `schema_errors` or rejected if it doesn't match; `SCHEMA_DIR` replaces
+//! generated schemas by published ones, so drift from those is caught.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+use jsonschema::JSONSchema;
+use schemars::generate::SchemaSettings;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::convert::{usage_error, write_json};
+use crate::correlation::{CorrelationCompleted, CorrelationTimedOut};
+use crate::mapping::{EventMapping, FieldType};
+use crate::{event_types, GenericEvent, Transformer};
+
+pub const USAGE: &str = "Usage: amqp2elastic schema [--output-dir DIR] [EVENT...]
+
+Print the JSON Schemas of the documents amqp2elastic emits, by event name
+(root tag): of the built-in event types, those in MAPPING_FILE, the derived
+correlation_completed and correlation_timed_out documents, and `generic` for
+UNKNOWN_EVENTS=generic. Only those of the given EVENTs, if any; just the
+schema itself for a single EVENT. With --output-dir, write each to
+DIR/<event>.schema.json instead, the layout SCHEMA_DIR expects.";
+
+/// The name of the schema of generic documents.
+pub const GENERIC: &str = "generic";
+
+/// What to do with a document that doesn't match its schema.
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SchemaValidation {
+    /// Don't validate the documents.
+    Off,
+    /// Send it anyway, with the violations in `schema_errors`.
+    Flag,
+    /// Reject the message (to the DLX).
+    Reject,
+}
+
+/// Why the schemas could not be loaded or compiled.
+#[derive(Debug)]
+pub enum SchemaError {
+    Read(PathBuf, io::Error),
+    Json(PathBuf, serde_json::Error),
+    Invalid(String, String),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaError::Read(path, err) => write!(f, "Cannot read schema {}: {}", path.display(), err),
+            SchemaError::Json(path, err) => write!(f, "Invalid JSON in schema {}: {}", path.display(), err),
+            SchemaError::Invalid(name, err) => write!(f, "Invalid schema for {}: {}", name, err),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+/// The schema of `E` as serialized, without the annotations that are only
+/// about deserializing the XML (eg. `default`).
+fn generate<E: JsonSchema>(name: &str) -> Value {
+    let generator = SchemaSettings::draft07().for_serialize().into_generator();
+    let mut schema = generator.into_root_schema_for::<E>().to_value();
+    strip(&mut schema, &["default", "readOnly"]);
+    schema["title"] = Value::from(name);
+    schema
+}
+
+fn strip(schema: &mut Value, keywords: &[&str]) {
+    match schema {
+        Value::Object(fields) => {
+            for keyword in keywords {
+                fields.remove(*keyword);
+            }
+            for (name, value) in fields.iter_mut() {
+                match (name.as_str(), value) {
+                    // Keyed by property name: a property may be called `default`
+                    ("properties" | "definitions" | "$defs", Value::Object(schemas)) => {
+                        schemas.values_mut().for_each(|schema| strip(schema, keywords))
+                    },
+                    (_, value) => strip(value, keywords),
+                }
+            }
+        },
+        Value::Array(values) => values.iter_mut().for_each(|value| strip(value, keywords)),
+        _ => {},
+    }
+}
+
+/// The schema of the documents of a built-in event type: its struct, with
+/// the fields of `add_identified` and no others.
+pub fn event_schema<E: JsonSchema>(name: &str) -> Value {
+    let mut schema = generate::<E>(name);
+    add_identified(&mut schema);
+    schema["additionalProperties"] = Value::Bool(false);
+    schema
+}
+
+/// Every document starts with its `event_id`, and has the `schema_errors`
+/// it was flagged with, if any; see `Identified`.
+fn add_identified(schema: &mut Value) {
+    if let Some(Value::Object(properties)) = schema.get_mut("properties") {
+        properties.insert("event_id".to_string(), json!({ "type": "string" }));
+        properties.insert("schema_errors".to_string(), schema_errors());
+    }
+    let required = schema.as_object_mut()
+        .map(|schema| schema.entry("required").or_insert_with(|| json!([])));
+    if let Some(Value::Array(required)) = required {
+        if !required.iter().any(|name| name == "event_id") {
+            required.insert(0, Value::from("event_id"));
+        }
+    }
+}
+
+fn schema_errors() -> Value {
+    json!({ "type": "array", "items": { "type": "string" } })
+}
+
+/// The schema of generic documents: the common fields, and any others.
+pub fn generic_schema() -> Value {
+    let mut schema = generate::<GenericEvent>(GENERIC);
+    add_identified(&mut schema);
+    schema
+}
+
+/// The schema of an event type declared in the mapping file.
+pub fn mapped_schema(name: &str, event: &EventMapping) -> Value {
+    let mut properties = Map::new();
+    let mut required = vec![Value::from("event_id")];
+    for (field, schema) in &[
+        ("event_id", json!({ "type": "string" })),
+        ("event_name", json!({ "type": "string" })),
+        ("event_namespace", json!({ "type": "string" })),
+        ("event_timestamp", json!({ "type": "string", "format": "date-time" })),
+        ("event_handle_timestamp", json!({ "type": "string" })),
+        ("event_payload", json!({ "type": "string" })),
+        ("origin", json!({ "type": "string" })),
+        ("schema_errors", schema_errors()),
+    ] {
+        properties.insert(field.to_string(), schema.clone());
+        if !["event_id", "event_namespace", "schema_errors"].contains(field) {
+            required.push(Value::from(*field));
+        }
+    }
+    for (field, mapping) in &event.fields {
+        let schema = match mapping.kind {
+            FieldType::String => json!({ "type": "string" }),
+            FieldType::Integer => json!({ "type": "integer" }),
+            FieldType::Float => json!({ "type": "number" }),
+            FieldType::Boolean => json!({ "type": "boolean" }),
+            FieldType::Timestamp => json!({ "type": "string", "format": "date-time" }),
+        };
+        let schema = if mapping.repeated { json!({ "type": "array", "items": schema }) } else { schema };
+        properties.insert(field.clone(), schema);
+        if mapping.required {
+            required.push(Value::from(field.as_str()));
+        }
+    }
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": name,
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
+
+/// The schemas of all documents, by event name; a mapping file declaration
+/// replaces the built-in event type, like when transforming.
+pub fn schemas(transformer: &Transformer) -> BTreeMap<String, Value> {
+    let mut schemas = BTreeMap::new();
+    for event_type in event_types() {
+        schemas.insert(event_type.root_tag.to_string(), event_type.schema());
+    }
+    for (name, event) in &transformer.mapping.events {
+        schemas.insert(name.clone(), mapped_schema(name, event));
+    }
+    let completed = event_schema::<CorrelationCompleted>("correlation_completed");
+    schemas.insert("correlation_completed".to_string(), completed);
+    let timed_out = event_schema::<CorrelationTimedOut>("correlation_timed_out");
+    schemas.insert("correlation_timed_out".to_string(), timed_out);
+    schemas.insert(GENERIC.to_string(), generic_schema());
+    schemas
+}
+
+/// Read the `*.schema.json` files in `dir`, by their `title` (or file name).
+pub fn load_dir(dir: &Path) -> Result<BTreeMap<String, Value>, SchemaError> {
+    let read_error = |err| SchemaError::Read(dir.to_path_buf(), err);
+    let mut schemas = BTreeMap::new();
+    for entry in fs::read_dir(dir).map_err(read_error)? {
+        let path = entry.map_err(read_error)?.path();
+        let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let stem = match file_name.strip_suffix(".schema.json") {
+            Some(stem) => stem.to_string(),
+            None => continue,
+        };
+        let content = fs::read_to_string(&path).map_err(|err| SchemaError::Read(path.clone(), err))?;
+        let schema: Value = serde_json::from_str(&content).map_err(|err| SchemaError::Json(path.clone(), err))?;
+        let name = schema["title"].as_str().map_or(stem, str::to_string);
+        schemas.insert(name, schema);
+    }
+    Ok(schemas)
+}
+
+/// The compiled schemas to check the documents with.
+#[derive(Debug)]
+pub struct Validator {
+    pub mode: SchemaValidation,
+    schemas: BTreeMap<String, JSONSchema>,
+}
+
+impl Validator {
+    pub fn new(mode: SchemaValidation, schemas: BTreeMap<String, Value>) -> Result<Validator, SchemaError> {
+        let schemas = schemas.into_iter()
+            .map(|(name, schema)| match JSONSchema::compile(&schema) {
+                Ok(compiled) => Ok((name, compiled)),
+                Err(err) => Err(SchemaError::Invalid(name, err.to_string())),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Validator { mode, schemas })
+    }
+
+    /// The generated schemas, with those in `SCHEMA_DIR` (if set) instead.
+    pub fn from_config(
+        mode: SchemaValidation,
+        schema_dir: Option<&str>,
+        transformer: &Transformer,
+    ) -> Result<Validator, SchemaError> {
+        let mut schemas = schemas(transformer);
+        if let Some(dir) = schema_dir {
+            schemas.extend(load_dir(Path::new(dir))?);
+        }
+        Validator::new(mode, schemas)
+    }
+
+    /// What is wrong with `document`, a document of event type `name`.
+    pub fn validate(&self, name: &str, document: &Value) -> Vec<String> {
+        let schema = match self.schemas.get(name) {
+            Some(schema) => schema,
+            None => return vec![format!("No schema for {}", name)],
+        };
+        match schema.validate(document) {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors
+                .map(|err| match err.instance_path.to_string().as_str() {
+                    "" => err.to_string(),
+                    path => format!("{}: {}", path, err),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A file name for the schema of event `name`, which can be a namespaced
+/// `{uri}localName`.
+fn file_name(name: &str) -> String {
+    let safe: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.' { c } else { '_' })
+        .collect();
+    format!("{}.schema.json", safe.trim_start_matches('_'))
+}
+
+/// Run the `schema` subcommand; returns the exit code.
+pub fn main(args: &[String], transformer: &Transformer) -> i32 {
+    let mut output_dir: Option<&str> = None;
+    let mut names = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output-dir" => match args.next() {
+                Some(dir) => output_dir = Some(dir),
+                None => return usage_error("--output-dir needs a directory", USAGE),
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return 0;
+            },
+            flag if flag.starts_with('-') => return usage_error(&format!("Unknown option {}", flag), USAGE),
+            name => names.push(name.to_string()),
+        }
+    }
+    let mut schemas = schemas(transformer);
+    if !names.is_empty() {
+        if let Some(unknown) = names.iter().find(|name| !schemas.contains_key(*name)) {
+            eprintln!("No schema for {}", unknown);
+            return 1;
+        }
+        schemas.retain(|name, _| names.contains(name));
+    }
+    let result = match output_dir {
+        Some(dir) => schemas.iter().try_for_each(|(name, schema)| {
+            let file = File::create(Path::new(dir).join(file_name(name)))?;
+            write_json(schema, &mut BufWriter::new(file))
+        }),
+        None if names.len() == 1 => write_json(&schemas[&names[0]], &mut io::stdout().lock()),
+        None => write_json(&json!(schemas), &mut io::stdout().lock()),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("{}", err);
+            2
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Mapping, Source, TransformError};
+    use xmltree::Element;
+
+    const BODY: &str = r##"<essenceLinkedEvent>
+  <timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <file>AB00112233.mxf</file>
+  <mediaId>AB00112233</mediaId>
+</essenceLinkedEvent>"##;
+
+    fn transformer(mode: SchemaValidation, schemas: BTreeMap<String, Value>) -> Transformer {
+        let validator = Validator::new(mode, schemas).unwrap();
+        Transformer { validator: Some(std::sync::Arc::new(validator)), ..Transformer::default() }
+    }
+
+    fn handle(transformer: &Transformer, body: &str) -> Result<Value, TransformError> {
+        let xml = Element::parse(body.as_bytes()).unwrap();
+        let document = transformer.handle_xml(xml, body, &Source::default())?;
+        Ok(serde_json::from_str(&document.json).unwrap())
+    }
+
+    #[test]
+    fn test_event_schema() {
+        let schemas = schemas(&Transformer::default());
+        let schema = &schemas["getMetadataResponse"];
+        assert_eq!(schema["title"], "getMetadataResponse");
+        assert_eq!(schema["additionalProperties"], false);
+        assert_eq!(schema["properties"]["event_timestamp"]["format"], "date-time");
+        let required = schema["required"].as_array().unwrap();
+        assert_eq!(required[0], "event_id");
+        assert!(required.contains(&json!("event_payload")));
+        // Deserialization defaults are not part of the contract
+        assert!(schema["properties"]["event_name"].get("default").is_none());
+        assert!(schemas.contains_key("correlation_completed"));
+        assert_eq!(schemas[GENERIC]["properties"]["event_known"]["type"], "boolean");
+    }
+
+    #[test]
+    fn test_documents_match_their_schema() {
+        let transformer = transformer(SchemaValidation::Reject, schemas(&Transformer::default()));
+        let json = handle(&transformer, BODY).unwrap();
+        assert!(json.get("schema_errors").is_none());
+
+        let generic = Transformer { unknown_events: crate::UnknownEvents::Generic, ..transformer };
+        assert!(handle(&generic, "<pingEvent><sentAt>now</sentAt></pingEvent>").is_ok());
+    }
+
+    #[test]
+    fn test_mapped_schema() {
+        let mapping = Mapping::load(Path::new("mapping.example.yaml")).unwrap();
+        let mapped = Transformer { mapping, ..Transformer::default() };
+        let schemas = schemas(&mapped);
+        let schema = &schemas["subtitleAvailableEvent"];
+        assert_eq!(schema["properties"]["subtitle_paths"]["items"]["type"], "string");
+        assert!(schema["required"].as_array().unwrap().contains(&json!("media_id")));
+
+        let validator = Validator::new(SchemaValidation::Reject, schemas).unwrap();
+        let body = r##"<subtitleAvailableEvent>
+  <timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <mediaId>AB00112233</mediaId>
+  <subtitle language="nl"><path>/subs/AB00112233.srt</path></subtitle>
+</subtitleAvailableEvent>"##;
+        let checked = Transformer { validator: Some(std::sync::Arc::new(validator)), ..mapped };
+        assert!(handle(&checked, body).is_ok());
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut schemas = schemas(&Transformer::default());
+        // A published schema that still calls it `mediaId`
+        let schema = schemas.get_mut("essenceLinkedEvent").unwrap();
+        schema["properties"]["mediaId"] = json!({ "type": "string" });
+        schema["required"].as_array_mut().unwrap().push(json!("mediaId"));
+
+        let flagging = transformer(SchemaValidation::Flag, schemas.clone());
+        let json = handle(&flagging, BODY).unwrap();
+        assert_eq!(json["schema_errors"], json!(["\"mediaId\" is a required property"]));
+        assert_eq!(json["media_id"], "AB00112233");
+        // A flagged document still matches the schema we publish
+        let published = Validator::new(SchemaValidation::Reject, super::schemas(&Transformer::default())).unwrap();
+        assert_eq!(published.validate("essenceLinkedEvent", &json), Vec::<String>::new());
+
+        let rejecting = transformer(SchemaValidation::Reject, schemas);
+        match handle(&rejecting, BODY) {
+            Err(err @ TransformError::SchemaViolation { .. }) => assert_eq!(err.kind(), "schema_violation"),
+            other => panic!("expected SchemaViolation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_load_dir() {
+        let dir = std::env::temp_dir().join(format!("amqp2elastic-schemas-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let schema = json!({ "title": "{http://example.com/ns}event", "type": "object" });
+        fs::write(dir.join(file_name("{http://example.com/ns}event")), schema.to_string()).unwrap();
+        fs::write(dir.join("notes.txt"), "not a schema").unwrap();
+        let schemas = load_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(schemas.keys().collect::<Vec<_>>(), vec!["{http://example.com/ns}event"]);
+        assert_eq!(file_name("{http://example.com/ns}event"), "http___example.com_ns_event.schema.json");
+    }
+}
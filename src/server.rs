@@ -0,0 +1,97 @@
+//! Embedded HTTP server for operational endpoints: `/metrics` for
+//! Prometheus, `/healthz` and `/readyz` for liveness and readiness probes.
+
+use std::error::Error;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::thread;
+
+use tiny_http::{Header, Request, Response, Server};
+
+use crate::{health, metrics};
+
+type HttpResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// Start serving on `addr` (eg. `0.0.0.0:8080`) in a background thread.
+/// Returns the address actually bound, which differs for port 0.
+pub fn start(addr: &str) -> HttpResult<SocketAddr> {
+    let server = Server::http(addr)?;
+    let local_addr = server.server_addr().to_ip().ok_or("Not listening on an IP address")?;
+    thread::Builder::new()
+        .name(String::from("http"))
+        .spawn(move || serve(server))?;
+    info!("Serving metrics and health checks on http://{}", local_addr);
+    Ok(local_addr)
+}
+
+fn serve(server: Server) {
+    for request in server.incoming_requests() {
+        let response = route(&request);
+        if let Err(e) = request.respond(response) {
+            debug!("Failed to respond to HTTP request: {}", e);
+        }
+    }
+}
+
+fn route(request: &Request) -> Response<Cursor<Vec<u8>>> {
+    let path = request.url().split('?').next().unwrap_or_default();
+    match path {
+        "/metrics" => Response::from_string(metrics::render())
+            .with_header(content_type(prometheus::TEXT_FORMAT)),
+        "/healthz" => probe(health::status().live),
+        "/readyz" => probe(health::status().ready),
+        _ => Response::from_string("Not found\n").with_status_code(404),
+    }
+}
+
+/// 200 if the probe passes, 503 otherwise, with the status as JSON.
+fn probe(ok: bool) -> Response<Cursor<Vec<u8>>> {
+    let body = serde_json::to_string(&health::status()).unwrap_or_default();
+    Response::from_string(body)
+        .with_header(content_type("application/json"))
+        .with_status_code(if ok { 200 } else { 503 })
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], value.as_bytes()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_endpoint() {
+        metrics::init();
+        let addr = start("127.0.0.1:0").unwrap();
+
+        let response = ureq::get(&format!("http://{}/metrics", addr)).call().unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response.into_string().unwrap().contains("amqp2elastic_prefetch_count"));
+
+        match ureq::get(&format!("http://{}/nothing", addr)).call() {
+            Err(ureq::Error::Status(status, _)) => assert_eq!(status, 404),
+            other => panic!("expected 404, got {:?}", other.map(|r| r.status())),
+        }
+    }
+
+    #[test]
+    fn test_health_endpoints() {
+        let addr = start("127.0.0.1:0").unwrap();
+
+        let response = ureq::get(&format!("http://{}/healthz", addr)).call().unwrap();
+        assert_eq!(response.status(), 200);
+        let status: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(status["live"], true);
+
+        // Nothing marks the output ready in tests.
+        match ureq::get(&format!("http://{}/readyz", addr)).call() {
+            Err(ureq::Error::Status(status, response)) => {
+                assert_eq!(status, 503);
+                let status: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+                assert_eq!(status["output_ready"], false);
+            },
+            other => panic!("expected 503, got {:?}", other.map(|r| r.status())),
+        }
+    }
+}
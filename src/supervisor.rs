@@ -0,0 +1,165 @@
+//! Supervision of the connection to the broker: connection state tracking
+//! and exponential backoff with jitter for reconnecting.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::{health, metrics};
+use crate::Config;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Delay before the first retry.
+    pub initial: Duration,
+    /// Upper bound for the delay between retries.
+    pub max: Duration,
+    /// Factor by which the delay grows after every failed attempt.
+    pub multiplier: f64,
+    /// Give up after this many consecutive failed attempts; 0 retries forever.
+    pub max_attempts: u32,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &Config) -> RetryPolicy {
+        RetryPolicy {
+            initial: Duration::from_millis(config.amqp_retry_initial_ms),
+            max: Duration::from_millis(config.amqp_retry_max_ms),
+            multiplier: config.amqp_retry_multiplier,
+            max_attempts: config.amqp_retry_max_attempts,
+        }
+    }
+
+    /// The delay without jitter before retry number `attempt` (starting at 1).
+    pub fn base_delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let millis = self.initial.as_millis() as f64 * self.multiplier.max(1.0).powi(exponent);
+        Duration::from_millis(millis.min(self.max.as_millis() as f64) as u64)
+    }
+
+    /// The delay before retry number `attempt` (starting at 1): half of the
+    /// base delay is fixed, the other half is random, so that several
+    /// instances don't all reconnect at the same moment.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = self.base_delay(attempt).as_millis() as u64;
+        let half = base / 2;
+        let jitter = if base > half { rand::thread_rng().gen_range(0..=base - half) } else { 0 };
+        Duration::from_millis(half + jitter)
+    }
+
+    /// Whether we should give up after `attempt` failed attempts.
+    pub fn is_exhausted(&self, attempt: u32) -> bool {
+        self.max_attempts != 0 && attempt >= self.max_attempts
+    }
+}
+
+/// State of the connection to the broker.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Consuming,
+    Disconnected,
+    Closed,
+}
+
+/// Tracks the connection state, logging every transition, and decides when
+/// to reconnect after a failure.
+pub struct Supervisor {
+    policy: RetryPolicy,
+    state: ConnectionState,
+    failed_attempts: u32,
+}
+
+impl Supervisor {
+    pub fn new(policy: RetryPolicy) -> Supervisor {
+        Supervisor {
+            policy,
+            state: ConnectionState::Disconnected,
+            failed_attempts: 0,
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    pub fn transition(&mut self, state: ConnectionState) {
+        if state != self.state {
+            info!("Connection state: {:?} -> {:?}", self.state, state);
+            self.state = state;
+            metrics::set_connection_state(state);
+            health::set_connection_state(state);
+        }
+        if state == ConnectionState::Consuming {
+            self.failed_attempts = 0;
+        }
+    }
+
+    /// Record a failed connection attempt or a lost connection. Returns the
+    /// delay before reconnecting, or `None` when we should give up.
+    pub fn on_failure(&mut self, reason: &str) -> Option<Duration> {
+        self.transition(ConnectionState::Disconnected);
+        self.failed_attempts += 1;
+        warn!("Connection failed or lost: {}", reason);
+        if self.policy.is_exhausted(self.failed_attempts) {
+            error!("Giving up after {} attempts", self.failed_attempts);
+            return None;
+        }
+        let delay = self.policy.delay(self.failed_attempts);
+        info!("Reconnecting in {:?} (attempt {})", delay, self.failed_attempts);
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(1000),
+            multiplier: 2.0,
+            max_attempts: 5,
+        }
+    }
+
+    #[test]
+    fn test_base_delay_grows_and_is_capped() {
+        let policy = policy();
+        assert_eq!(policy.base_delay(1), Duration::from_millis(100));
+        assert_eq!(policy.base_delay(2), Duration::from_millis(200));
+        assert_eq!(policy.base_delay(4), Duration::from_millis(800));
+        assert_eq!(policy.base_delay(5), Duration::from_millis(1000));
+        assert_eq!(policy.base_delay(100), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_delay_has_bounded_jitter() {
+        let policy = policy();
+        for _ in 0..100 {
+            let delay = policy.delay(3);
+            assert!(delay >= Duration::from_millis(200) && delay <= Duration::from_millis(400));
+        }
+    }
+
+    #[test]
+    fn test_is_exhausted() {
+        let mut policy = policy();
+        assert!(!policy.is_exhausted(4));
+        assert!(policy.is_exhausted(5));
+        policy.max_attempts = 0;
+        assert!(!policy.is_exhausted(1000));
+    }
+
+    #[test]
+    fn test_supervisor_resets_attempts_when_consuming() {
+        let mut supervisor = Supervisor::new(RetryPolicy { max_attempts: 2, ..policy() });
+        assert!(supervisor.on_failure("connection refused").is_some());
+        supervisor.transition(ConnectionState::Consuming);
+        assert!(supervisor.on_failure("connection reset").is_some());
+        assert!(supervisor.on_failure("connection refused").is_none());
+        assert_eq!(supervisor.state(), ConnectionState::Disconnected);
+    }
+}
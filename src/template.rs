@@ -0,0 +1,398 @@
+//! The Elasticsearch index template for the documents we emit, and the
+//! `mapping` subcommand that prints it.
+//!
+//! The field types are declared in `FIELDS`, by their dot-separated path;
+//! a test transforms a sample of every event type to keep them in sync with
+//! the event structs. Fields of declared (mapped) event types get the type of
+//! their declaration; other strings, eg. of generic documents, are mapped as
+//! `keyword` instead of relying on dynamic mapping.
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+
+use serde_json::{json, Map, Value};
+
+use crate::convert::{usage_error, write_json};
+use crate::mapping::FieldType;
+use crate::{Config, Transformer};
+
+pub const USAGE: &str = "Usage: amqp2elastic mapping [--output FILE]
+
+Print the composable Elasticsearch index template for the documents
+amqp2elastic emits, with explicit mappings for the fields of every event
+type (including those in MAPPING_FILE). Its index pattern is derived from
+ES_INDEX_PATTERN. Install it with eg.
+
+    curl -XPUT \"$ES_URL/_index_template/vrt-events\" \\
+        -H 'Content-Type: application/json' -d @template.json";
+
+/// How a field is indexed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldKind {
+    /// Identifiers and enumerations: exact match and aggregations.
+    Keyword,
+    /// Free text, with a `keyword` subfield.
+    Text,
+    Date,
+    Long,
+    Double,
+    Boolean,
+    /// The raw message: stored in `_source`, but not indexed.
+    Payload,
+    /// An object with arbitrary fields, mapped dynamically.
+    Object,
+}
+
+impl FieldKind {
+    fn mapping(self) -> Value {
+        match self {
+            FieldKind::Keyword => json!({ "type": "keyword", "ignore_above": 1024 }),
+            FieldKind::Text => json!({
+                "type": "text",
+                "fields": { "keyword": { "type": "keyword", "ignore_above": 256 } },
+            }),
+            FieldKind::Date => json!({ "type": "date" }),
+            FieldKind::Long => json!({ "type": "long" }),
+            FieldKind::Double => json!({ "type": "double" }),
+            FieldKind::Boolean => json!({ "type": "boolean" }),
+            FieldKind::Payload => json!({ "type": "text", "index": false }),
+            FieldKind::Object => json!({ "type": "object", "dynamic": true }),
+        }
+    }
+}
+
+impl From<FieldType> for FieldKind {
+    fn from(field_type: FieldType) -> FieldKind {
+        match field_type {
+            FieldType::String => FieldKind::Keyword,
+            FieldType::Integer => FieldKind::Long,
+            FieldType::Float => FieldKind::Double,
+            FieldType::Boolean => FieldKind::Boolean,
+            FieldType::Timestamp => FieldKind::Date,
+        }
+    }
+}
+
+/// The fields of the built-in event types and of the documents derived by
+/// the correlation tracker.
+pub const FIELDS: &[(&str, FieldKind)] = &[
+    ("event_id", FieldKind::Keyword),
+    ("event_name", FieldKind::Keyword),
+    ("event_namespace", FieldKind::Keyword),
+    ("event_known", FieldKind::Boolean),
+    ("event_timestamp", FieldKind::Date),
+    ("event_handle_timestamp", FieldKind::Date),
+    ("event_payload", FieldKind::Payload),
+    ("origin", FieldKind::Keyword),
+    ("file", FieldKind::Keyword),
+    ("pid", FieldKind::Keyword),
+    ("md5sum", FieldKind::Keyword),
+    ("s3_bucket", FieldKind::Keyword),
+    ("media_id", FieldKind::Keyword),
+    ("correlation_id", FieldKind::Keyword),
+    ("destination_path", FieldKind::Keyword),
+    ("ot_type", FieldKind::Keyword),
+    ("subtitle_path", FieldKind::Keyword),
+    ("subtitle_format", FieldKind::Keyword),
+    ("metadata.titles.type", FieldKind::Keyword),
+    ("metadata.titles.value", FieldKind::Text),
+    ("metadata.descriptions.type", FieldKind::Keyword),
+    ("metadata.descriptions.value", FieldKind::Text),
+    // Free-form dates, eg. `2021-02-03` or `vandaag`
+    ("metadata.dates.type", FieldKind::Keyword),
+    ("metadata.dates.value", FieldKind::Keyword),
+    ("metadata.program_id", FieldKind::Keyword),
+    ("metadata.program_title", FieldKind::Text),
+    ("metadata.series_id", FieldKind::Keyword),
+    ("metadata.series_title", FieldKind::Text),
+    ("metadata.extra", FieldKind::Object),
+    ("request_event_name", FieldKind::Keyword),
+    ("response_event_name", FieldKind::Keyword),
+    ("expected_event_name", FieldKind::Keyword),
+    ("request_event_id", FieldKind::Keyword),
+    ("response_event_id", FieldKind::Keyword),
+    ("request_timestamp", FieldKind::Date),
+    ("response_timestamp", FieldKind::Date),
+    ("duration_ms", FieldKind::Long),
+    ("timeout_ms", FieldKind::Long),
+    ("schema_errors", FieldKind::Text),
+];
+
+/// All fields with their kind: `FIELDS`, and those of the event types
+/// declared in the mapping file. A declared field can't change the kind of
+/// a built-in one.
+pub fn fields(transformer: &Transformer) -> Vec<(String, FieldKind)> {
+    let mut fields: Vec<(String, FieldKind)> = FIELDS.iter().map(|(path, kind)| (path.to_string(), *kind)).collect();
+    for (root_tag, event) in &transformer.mapping.events {
+        for (name, field) in &event.fields {
+            let kind = FieldKind::from(field.kind);
+            match fields.iter().find(|(path, _)| path == name) {
+                Some((_, existing)) if *existing != kind => {
+                    warn!("Field {} of {} is mapped as {:?}, not {:?}", name, root_tag, existing, kind);
+                },
+                Some(_) => {},
+                None => fields.push((name.clone(), kind)),
+            }
+        }
+    }
+    fields
+}
+
+/// The `index_patterns` for a `strftime` index name pattern: everything up
+/// to the first conversion, eg. `vrt-events-*` for `vrt-events-%Y.%m`.
+pub fn index_patterns(index_pattern: &str) -> Vec<String> {
+    match index_pattern.find('%') {
+        Some(i) => vec![format!("{}*", &index_pattern[..i])],
+        None => vec![index_pattern.to_string()],
+    }
+}
+
+/// The composable index template (for `PUT _index_template/<name>`).
+pub fn index_template(index_pattern: &str, transformer: &Transformer) -> Value {
+    let mut properties = Map::new();
+    for (path, kind) in fields(transformer) {
+        insert(&mut properties, &path, kind);
+    }
+    json!({
+        "index_patterns": index_patterns(index_pattern),
+        "priority": 100,
+        "template": {
+            "mappings": {
+                "date_detection": false,
+                "dynamic_templates": [{
+                    "strings_as_keywords": {
+                        "match_mapping_type": "string",
+                        "mapping": FieldKind::Keyword.mapping(),
+                    },
+                }],
+                "properties": properties,
+            },
+        },
+        "_meta": {
+            "description": "Documents emitted by amqp2elastic",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+    })
+}
+
+/// Add a field by its dotted path, as nested object `properties`.
+fn insert(properties: &mut Map<String, Value>, path: &str, kind: FieldKind) {
+    match path.split_once('.') {
+        Some((parent, rest)) => {
+            let parent = properties.entry(parent).or_insert_with(|| json!({ "properties": {} }));
+            if let Some(Value::Object(children)) = parent.get_mut("properties") {
+                insert(children, rest, kind);
+            }
+        },
+        None => {
+            properties.insert(path.to_string(), kind.mapping());
+        },
+    }
+}
+
+/// Run the `mapping` subcommand; returns the exit code.
+pub fn main(args: &[String], config: &Config, transformer: &Transformer) -> i32 {
+    let mut output: Option<&str> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => match args.next() {
+                Some(path) => output = Some(path),
+                None => return usage_error("--output needs a file", USAGE),
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return 0;
+            },
+            other => return usage_error(&format!("Unknown argument {}", other), USAGE),
+        }
+    }
+    let template = index_template(&config.es_index_pattern, transformer);
+    let result = match output {
+        Some(path) => File::create(path).and_then(|file| write_json(&template, &mut BufWriter::new(file))),
+        None => write_json(&template, &mut io::stdout().lock()),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("{}", err);
+            2
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{event_types, schema, timestamp, Correlated, Document, Mapping, Tracker, UnknownEvents};
+    use crate::{SchemaValidation, Source, Validator};
+    use chrono::{Duration, Utc};
+    use std::path::Path;
+    use std::sync::Arc;
+    use xmltree::Element;
+
+    /// A message of every built-in event type, with all optional fields.
+    const SAMPLES: &[&str] = &[
+        r##"<essenceArchivedEvent xmlns="http://www.vrt.be/mam/viaa">
+  <timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <file>AB00112233.mxf</file><pid>AB00112233</pid><md5sum>1234abcd</md5sum><s3bucket>bucket</s3bucket>
+</essenceArchivedEvent>"##,
+        r##"<essenceLinkedEvent><timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <file>AB00112233.mxf</file><mediaId>AB00112233</mediaId>
+</essenceLinkedEvent>"##,
+        r##"<essenceUnlinkedEvent><timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <mediaId>AB00112233</mediaId>
+</essenceUnlinkedEvent>"##,
+        r##"<objectDeletedEvent><timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <mediaId>AB00112233</mediaId>
+</objectDeletedEvent>"##,
+        r##"<getMetadataRequest><timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <mediaId>AB00112233</mediaId><correlationId>a1b2c3d4</correlationId>
+</getMetadataRequest>"##,
+        r##"<getMetadataResponse><timestamp>2021-02-03T20:21:03.032+01:00</timestamp>
+  <correlationId>a1b2c3d4</correlationId>
+  <metadata>
+    <title type="original">De Ideale Wereld</title><shortDescription>Satire</shortDescription>
+    <broadcastDate>2021-02-03</broadcastDate>
+    <programId>P0123</programId><programTitle>DIW</programTitle>
+    <seriesId>S0042</seriesId><seriesName>De Ideale Wereld</seriesName>
+    <rights><owner>VRT</owner></rights>
+  </metadata>
+</getMetadataResponse>"##,
+        r##"<metadataUpdatedEvent><timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <metadata><title>Het Journaal</title></metadata>
+</metadataUpdatedEvent>"##,
+        r##"<closedOtAvailableEvent><timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <ot><mediaId>AB00112233</mediaId><otType>CLOSED</otType><path>/ot/AB00112233.stl</path><format>stl</format></ot>
+</closedOtAvailableEvent>"##,
+        r##"<openOtAvailableEvent><timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <ot><mediaId>AB00112233</mediaId><otType>OPEN</otType><path>/ot/AB00112233.srt</path><format>srt</format></ot>
+</openOtAvailableEvent>"##,
+        r##"<makeSubtitleAvailableRequest><timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <correlationId>a1b2c3d4</correlationId><id>AB00112233</id>
+  <destinationPath>/subs/</destinationPath><otType>CLOSED</otType>
+</makeSubtitleAvailableRequest>"##,
+        r##"<triggerExportRequest><timestamp>2021-02-03T20:21:02.032+01:00</timestamp>
+  <mediaId>AB00112233</mediaId><file>AB00112233.mxf</file><correlationId>e5f6</correlationId>
+</triggerExportRequest>"##,
+        r##"<triggerExportResponse><timestamp>2021-02-03T20:21:04.032+01:00</timestamp>
+  <correlationId>e5f6</correlationId>
+</triggerExportResponse>"##,
+    ];
+
+    fn transform(transformer: &Transformer, body: &str) -> Document {
+        transformer.handle_xml(Element::parse(body.as_bytes()).unwrap(), body, &Source::default()).unwrap()
+    }
+
+    /// Every document we can emit: of each event type, flagged as not
+    /// matching its schema, and the derived ones.
+    fn documents() -> Vec<Document> {
+        let mut schemas = schema::schemas(&Transformer::default());
+        schemas.insert("essenceUnlinkedEvent".to_string(), json!({ "required": ["mediaId"] }));
+        let validator = Validator::new(SchemaValidation::Flag, schemas).unwrap();
+        let transformer = Transformer { validator: Some(Arc::new(validator)), ..Transformer::default() };
+        let mut tracker = Tracker::new(Duration::minutes(5), None);
+        let mut documents = Vec::new();
+        for body in SAMPLES {
+            let document = transform(&transformer, body);
+            documents.extend(document.correlation.as_ref().and_then(|event| tracker.observe(event)));
+            documents.push(document);
+        }
+        let request = Correlated {
+            event_name: "getMetadataRequest".to_string(),
+            correlation_id: "x".to_string(),
+            event_id: "req".to_string(),
+            timestamp: Utc::now(),
+            media_id: Some("AB00112233".to_string()),
+        };
+        tracker.observe(&request);
+        documents.extend(tracker.expire(Utc::now() + Duration::minutes(6)));
+        documents
+    }
+
+    /// The leaf values of a document by their dotted path; arrays are
+    /// multiple values of the same field.
+    fn leaves(path: &str, value: &Value, found: &mut Vec<(String, Value)>) {
+        match value {
+            Value::Object(fields) => for (name, value) in fields {
+                let path = if path.is_empty() { name.clone() } else { format!("{}.{}", path, name) };
+                leaves(&path, value, found);
+            },
+            Value::Array(values) => for value in values {
+                leaves(path, value, found);
+            },
+            value => found.push((path.to_string(), value.clone())),
+        }
+    }
+
+    fn check(fields: &[(String, FieldKind)], path: &str, value: &Value) {
+        let kind = fields.iter()
+            .find(|(field, kind)| {
+                field == path || (*kind == FieldKind::Object && path.starts_with(&format!("{}.", field)))
+            })
+            .map(|(_, kind)| *kind)
+            .unwrap_or_else(|| panic!("{} is not in the template", path));
+        let matches = match kind {
+            FieldKind::Keyword | FieldKind::Text | FieldKind::Payload | FieldKind::Object => value.is_string(),
+            FieldKind::Date => value.as_str().and_then(timestamp::parse).is_some(),
+            FieldKind::Long => value.is_i64() || value.is_u64(),
+            FieldKind::Double => value.is_number(),
+            FieldKind::Boolean => value.is_boolean(),
+        };
+        assert!(matches, "{} is mapped as {:?}, but is {}", path, kind, value);
+    }
+
+    #[test]
+    fn test_fields_in_sync_with_event_types() {
+        let tags: Vec<String> = SAMPLES.iter().map(|body| Element::parse(body.as_bytes()).unwrap().name).collect();
+        for event_type in event_types() {
+            assert!(tags.iter().any(|tag| tag == event_type.root_tag), "No sample for {}", event_type.root_tag);
+        }
+
+        let fields = fields(&Transformer::default());
+        let mut seen = Vec::new();
+        for document in documents() {
+            let mut values = Vec::new();
+            leaves("", &serde_json::from_str(&document.json).unwrap(), &mut values);
+            for (path, value) in values {
+                check(&fields, &path, &value);
+                seen.push(path);
+            }
+        }
+        // The generic fallback only adds `event_known`, its other fields are dynamic
+        let generic = Transformer { unknown_events: UnknownEvents::Generic, ..Transformer::default() };
+        let json: Value = serde_json::from_str(&transform(&generic, "<pingEvent/>").json).unwrap();
+        check(&fields, "event_known", &json["event_known"]);
+        seen.push("event_known".to_string());
+
+        for (path, _) in FIELDS {
+            assert!(seen.iter().any(|seen| seen == path || seen.starts_with(&format!("{}.", path))),
+                "{} is in the template, but not in any document", path);
+        }
+    }
+
+    #[test]
+    fn test_mapped_fields() {
+        let transformer = Transformer {
+            mapping: Mapping::load(Path::new("mapping.example.yaml")).unwrap(),
+            ..Transformer::default()
+        };
+        let fields = fields(&transformer);
+        assert!(fields.contains(&("subtitle_published".to_string(), FieldKind::Date)));
+        assert!(fields.contains(&("subtitle_languages".to_string(), FieldKind::Keyword)));
+        // Declared again, but still once
+        assert_eq!(fields.iter().filter(|(path, _)| path == "media_id").count(), 1);
+    }
+
+    #[test]
+    fn test_index_template() {
+        let template = index_template("vrt-events-%Y.%m", &Transformer::default());
+        assert_eq!(template["index_patterns"], json!(["vrt-events-*"]));
+        let properties = &template["template"]["mappings"]["properties"];
+        assert_eq!(properties["event_timestamp"]["type"], "date");
+        assert_eq!(properties["event_payload"]["index"], false);
+        assert_eq!(properties["media_id"]["type"], "keyword");
+        assert_eq!(properties["metadata"]["properties"]["titles"]["properties"]["value"]["type"], "text");
+        assert_eq!(index_patterns("vrt-events"), vec!["vrt-events"]);
+    }
+}
@@ -0,0 +1,90 @@
+//! Parsing and normalization of event timestamps.
+//!
+//! VRT sends timestamps in several RFC 3339-like variants, eg.
+//! `2021-02-03T20:21:02,032132132+01:00` (comma as decimal separator), which
+//! Elasticsearch's default date parser rejects. We parse them tolerantly and
+//! always emit RFC 3339 in UTC.
+
+use std::borrow::Cow;
+
+use chrono::prelude::*;
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+use serde::{de, Deserialize, Deserializer, Serializer};
+
+/// Prefix of the deserialization error for unparseable timestamps, see
+/// `TransformError::from_xml_error`.
+pub(crate) const INVALID_TIMESTAMP: &str = "invalid timestamp `";
+
+/// Parse any of the known timestamp variants.
+pub fn parse(value: &str) -> Option<DateTime<Utc>> {
+    let mut value = value.trim().replace(',', ".");
+    // Space instead of `T` between date and time
+    if value.len() > 10 && value.as_bytes()[10] == b' ' {
+        value.replace_range(10..11, "T");
+    }
+    DateTime::parse_from_rfc3339(&value)
+        // Offset without colon, eg. `+0100`
+        .or_else(|_| DateTime::parse_from_str(&value, "%Y-%m-%dT%H:%M:%S%.f%z"))
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .ok()
+}
+
+/// Format as RFC 3339 in UTC, eg. `2021-02-03T19:21:02.032132132Z`.
+pub fn format(timestamp: &DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+pub fn serialize<S: Serializer>(timestamp: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format(timestamp))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse(&value).ok_or_else(|| de::Error::custom(format!("{}{}`", INVALID_TIMESTAMP, value)))
+}
+
+/// Stands for a timestamp in the JSON Schemas of the documents, as in
+/// `#[schemars(with = "timestamp::Rfc3339")]`.
+pub struct Rfc3339;
+
+impl JsonSchema for Rfc3339 {
+    fn inline_schema() -> bool {
+        true
+    }
+
+    fn schema_name() -> Cow<'static, str> {
+        "Rfc3339".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({ "type": "string", "format": "date-time" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_variants() {
+        let expected = Utc.ymd(2021, 2, 3).and_hms_nano(19, 21, 2, 32_132_132);
+        assert_eq!(parse("2021-02-03T20:21:02,032132132+01:00"), Some(expected));
+        assert_eq!(parse("2021-02-03T20:21:02.032132132+01:00"), Some(expected));
+        assert_eq!(parse("2021-02-03T19:21:02.032132132Z"), Some(expected));
+        assert_eq!(parse("2021-02-03 20:21:02.032132132+01:00"), Some(expected));
+        assert_eq!(parse("2021-02-03T20:21:02.032132132+0100"), Some(expected));
+        assert_eq!(parse(" 2021-02-03T20:21:02+01:00\n"), Some(Utc.ymd(2021, 2, 3).and_hms(19, 21, 2)));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(parse("03/02/2021 20:21"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn test_format() {
+        let timestamp = parse("2021-02-03T20:21:02,032+01:00").unwrap();
+        assert_eq!(format(&timestamp), "2021-02-03T19:21:02.032Z");
+    }
+}
@@ -0,0 +1,116 @@
+//! TLS settings for `amqps://` connections to the broker.
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+use native_tls::{Certificate, Identity, TlsConnector};
+
+use crate::{setting, Config};
+
+/// Errors while setting up TLS from the `AMQP_TLS_*` settings.
+#[derive(Debug)]
+pub enum TlsSetupError {
+    /// A certificate or key file could not be read.
+    Read { path: String, source: io::Error },
+    /// The CA bundle is not a valid PEM certificate.
+    CaCertificate { path: String, source: native_tls::Error },
+    /// The client certificate and key do not form a valid (PEM) identity.
+    ClientIdentity { cert: String, key: String, source: native_tls::Error },
+    /// Only one of the client certificate and key was given.
+    IncompleteIdentity,
+    Connector(native_tls::Error),
+}
+
+impl fmt::Display for TlsSetupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsSetupError::Read { path, source } => {
+                write!(f, "Cannot read {}: {}", path, source)
+            },
+            TlsSetupError::CaCertificate { path, source } => {
+                write!(f, "Invalid CA certificate in {}: {}", path, source)
+            },
+            TlsSetupError::ClientIdentity { cert, key, source } => {
+                write!(f, "Invalid client certificate/key in {}/{}: {}", cert, key, source)
+            },
+            TlsSetupError::IncompleteIdentity => {
+                write!(f, "AMQP_TLS_CERT_FILE and AMQP_TLS_KEY_FILE must be set together")
+            },
+            TlsSetupError::Connector(source) => write!(f, "Cannot set up TLS: {}", source),
+        }
+    }
+}
+
+impl std::error::Error for TlsSetupError {}
+
+fn read(path: &str) -> Result<Vec<u8>, TlsSetupError> {
+    fs::read(path).map_err(|source| TlsSetupError::Read { path: path.to_string(), source })
+}
+
+/// Build the TLS connector described by the `AMQP_TLS_*` settings: an
+/// extra CA bundle, a client certificate and key (both PEM, the key in
+/// PKCS#8) and whether to verify the broker's hostname.
+pub fn tls_connector(config: &Config) -> Result<TlsConnector, TlsSetupError> {
+    let mut builder = TlsConnector::builder();
+    if let Some(path) = setting(&config.amqp_tls_ca_file) {
+        let certificate = Certificate::from_pem(&read(path)?)
+            .map_err(|source| TlsSetupError::CaCertificate { path: path.to_string(), source })?;
+        builder.add_root_certificate(certificate);
+    }
+    match (setting(&config.amqp_tls_cert_file), setting(&config.amqp_tls_key_file)) {
+        (Some(cert), Some(key)) => {
+            let identity = Identity::from_pkcs8(&read(cert)?, &read(key)?)
+                .map_err(|source| TlsSetupError::ClientIdentity {
+                    cert: cert.to_string(),
+                    key: key.to_string(),
+                    source,
+                })?;
+            builder.identity(identity);
+        },
+        (None, None) => {},
+        _ => return Err(TlsSetupError::IncompleteIdentity),
+    }
+    if !config.amqp_tls_verify_hostname {
+        warn!("Hostname verification of the broker's certificate is disabled");
+        builder.danger_accept_invalid_hostnames(true);
+    }
+    builder.build().map_err(TlsSetupError::Connector)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+    use std::env;
+
+    #[test]
+    fn test_defaults_build() {
+        assert!(tls_connector(&test_config(vec![])).is_ok());
+    }
+
+    #[test]
+    fn test_missing_ca_file() {
+        let config = test_config(vec![("AMQP_TLS_CA_FILE", "/nonexistent/ca.pem")]);
+        match tls_connector(&config) {
+            Err(TlsSetupError::Read { path, .. }) => assert_eq!(path, "/nonexistent/ca.pem"),
+            other => panic!("expected Read error, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_invalid_ca_file() {
+        let path = env::temp_dir().join("amqp2elastic-invalid-ca.pem");
+        fs::write(&path, "not a certificate").unwrap();
+        let config = test_config(vec![("AMQP_TLS_CA_FILE", path.to_str().unwrap())]);
+        let result = tls_connector(&config);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(TlsSetupError::CaCertificate { .. })));
+    }
+
+    #[test]
+    fn test_cert_without_key() {
+        let config = test_config(vec![("AMQP_TLS_CERT_FILE", "/etc/client.pem")]);
+        assert!(matches!(tls_connector(&config), Err(TlsSetupError::IncompleteIdentity)));
+    }
+}